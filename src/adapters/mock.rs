@@ -0,0 +1,114 @@
+//! An adapter that talks to no backend, for tests.
+//! The poster records every request and answers it as if it succeeded,
+//! the listener passes on whatever events tests inject.
+
+use std::sync::{Arc, Mutex};
+use tokio::{select, spawn, sync::mpsc::{self, UnboundedSender}};
+
+use crate::{POSTER, Status, adapters::{API, APIReceiver, APIRequest, APIResponse, APIWrapper, Listener}, objects::{Event, Permission, User}};
+
+pub struct MockListener {
+    pub events: UnboundedSender<Event>,
+    pub status: Status
+}
+
+impl Listener for MockListener {
+    async fn run(&mut self) {
+        self.status.stopped().await;
+    }
+}
+
+impl MockListener {
+    pub fn init(status: Status, events: UnboundedSender<Event>) -> Self {
+        Self { events, status }
+    }
+
+    /// Hands `event` to the receiver as if it came from a backend.
+    pub fn inject(&self, event: Event) -> bool {
+        self.events.send(event).is_ok()
+    }
+}
+
+pub struct MockPoster {
+    receiver: APIReceiver,
+    pub status: Status,
+    /// Every request handled so far, oldest first.
+    pub requests: Arc<Mutex<Vec<API>>>
+}
+
+impl MockPoster {
+    /// Makes the poster the global [POSTER].
+    pub fn init(status: Status) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel::<APIRequest>();
+        POSTER.lock().unwrap().replace(APIWrapper { sender: tx });
+        Self::new(rx, status)
+    }
+
+    /// A poster serving `receiver` only, leaving the global [POSTER] alone.
+    pub fn new(receiver: APIReceiver, status: Status) -> Self {
+        Self {
+            receiver,
+            status,
+            requests: Arc::new(Mutex::new(Vec::new()))
+        }
+    }
+
+    /// Starts a poster of its own, leaving the global [POSTER] alone, and returns
+    /// the wrapper to send through it and what it records. It runs until the test ends.
+    pub fn install() -> (APIWrapper, Arc<Mutex<Vec<API>>>) {
+        let (tx, rx) = mpsc::unbounded_channel::<APIRequest>();
+        let mut poster = Self::new(rx, Status::new());
+        let requests = poster.requests.clone();
+        spawn(async move { poster.run().await });
+        (APIWrapper { sender: tx }, requests)
+    }
+
+    pub fn handle(&self, req: APIRequest) {
+        let mut requests = self.requests.lock().unwrap();
+        let _ = req.resp_tx.send(Self::respond(&req.api, requests.len() + 1));
+        requests.push(req.api);
+    }
+
+    /// A successful response to `api`. Messages get `id` as their message id.
+    fn respond(api: &API, id: usize) -> APIResponse {
+        match api {
+            API::SendGroupMsg { .. } | API::SendPrivateMsg { .. }
+            | API::SendGroupText { .. } | API::SendPrivateText { .. } => {
+                APIResponse::SendMsgResult { success: true, message_id: id }
+            }
+            API::UploadGroupFile { .. } | API::UploadPrivateFile { .. } => {
+                APIResponse::UploadFileResult { success: true, file_id: id.to_string() }
+            }
+            API::SetGroupCard { .. } | API::SetGroupBan { .. } | API::SetGroupKick { .. }
+            | API::SetMsgEmojiLike { .. } => APIResponse::Ok,
+            API::GetFriendList => APIResponse::MemberList(Vec::new()),
+            API::GetStrangerInfo { user_id } => APIResponse::UserInfo(User {
+                user_id: *user_id,
+                nickname: None,
+                card: None,
+                role: Permission::Normal
+            })
+        }
+    }
+
+    pub async fn run(&mut self) {
+        loop {
+            select! {
+                Some(req) = self.receiver.recv() => {
+                    self.handle(req);
+                }
+                _ = self.status.stopped() => {
+                    *POSTER.lock().unwrap() = None;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// `events` receives every event injected into the listener.
+/// Requests aren't answered until [MockPoster::run] is called.
+pub fn get_pair(events: UnboundedSender<Event>) -> (MockListener, MockPoster) {
+    let status = Status::new();
+    (MockListener::init(status.clone(), events), MockPoster::init(status))
+}
@@ -1,14 +1,106 @@
-use tokio::sync::{mpsc::error::SendError, oneshot::{self, error::RecvError}};
+use std::{collections::VecDeque, time::{Duration, Instant}};
 
-use crate::objects::{Group, MessageArrayItem, User};
+use rand::Rng;
+use tokio::{sync::{mpsc::{UnboundedSender, error::SendError}, oneshot::{self, error::RecvError}}, task::{JoinError, JoinHandle}};
 
+use crate::{Status, config::{Config, Protocol}, get_config, get_logger, objects::{Event, Group, MessageArrayItem, User}};
+
+pub mod mock;
 pub mod napcat;
+pub mod satori;
 
 #[allow(async_fn_in_trait)]
 pub trait Listener {
     async fn run(&mut self);
 }
 
+/// A running listener and poster pair, whatever the protocol.
+pub struct Adapter {
+    status: Status,
+    thread: JoinHandle<()>
+}
+
+impl Adapter {
+    /// Stops the listener and poster and waits for them to end.
+    pub async fn close(self) -> Result<(), JoinError> {
+        self.status.stop();
+        self.thread.await
+    }
+}
+
+/// Starts the adapter for `config.network.protocol`, which sets [crate::POSTER].
+/// `events` receives every event the listener gets.
+pub fn init(config: &Config, events: UnboundedSender<Event>) -> Adapter {
+    let (status, thread) = match config.network.protocol {
+        Protocol::NapCat => {
+            let (listener, poster) = napcat::get_pair(events);
+            (listener.status.clone(), napcat::run_pair(listener, poster))
+        }
+        Protocol::Satori => {
+            let (listener, poster) = satori::get_pair(events);
+            (listener.status.clone(), satori::run_pair(listener, poster))
+        }
+    };
+    Adapter { status, thread }
+}
+
+/// How long a listener waits before reconnecting. Randomized, so that
+/// instances losing their backends at once don't all reconnect in step.
+pub fn reconnect_delay() -> Duration {
+    Duration::from_millis(rand::rng().random_range(2500..=3500))
+}
+
+/// Passes events on from a listener, dropping messages already passed on
+/// within `network.dedup`. Backends may deliver recent events again after
+/// reconnecting, which would otherwise be handled twice.
+pub struct EventSender {
+    events: UnboundedSender<Event>,
+    /// Ids of the messages passed on, oldest first.
+    recent: VecDeque<(usize, Instant)>
+}
+
+impl EventSender {
+    pub fn new(events: UnboundedSender<Event>) -> Self {
+        Self { events, recent: VecDeque::new() }
+    }
+
+    /// Passes `event` on unless it repeats a recent message.
+    /// Fails only if no one is receiving events.
+    pub fn send(&mut self, event: Event) -> Result<(), SendError<Event>> {
+        match &event {
+            Event::Message(msg) if self.is_repeat(msg.message_id) => {
+                get_logger().debug(&format!("Dropped repeated message {}", msg.message_id));
+                Ok(())
+            }
+            _ => self.events.send(event)
+        }
+    }
+
+    /// Whether `message_id` was seen within the window, recording it if not.
+    /// `0` stands for an unknown id and is never a repeat.
+    fn is_repeat(&mut self, message_id: usize) -> bool {
+        let config = get_config();
+        let window = Duration::from_secs(config.network.dedup.window_secs);
+        let capacity = config.network.dedup.capacity;
+
+        while self.recent.front().is_some_and(|(_, seen)| seen.elapsed() > window) {
+            self.recent.pop_front();
+        }
+        if message_id == 0 || window.is_zero() || capacity == 0 {
+            return false;
+        }
+        if self.recent.iter().any(|(id, _)| *id == message_id) {
+            return true;
+        }
+        while self.recent.len() >= capacity {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((message_id, Instant::now()));
+        false
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum API {
     SendGroupMsg {
         group_id: usize,
@@ -35,10 +127,43 @@ pub enum API {
         user_id: usize,
         file: String,
         name: String
+    },
+    /// Sets a member's group card, the name shown in that group.
+    /// An empty `card` removes it.
+    SetGroupCard {
+        group_id: usize,
+        user_id: usize,
+        card: String
+    },
+    /// Mutes a member for `duration_secs`. `0` unmutes them.
+    SetGroupBan {
+        group_id: usize,
+        user_id: usize,
+        duration_secs: u64
+    },
+    /// Removes a member from the group. With `reject_add_request`,
+    /// their later requests to join are rejected.
+    SetGroupKick {
+        group_id: usize,
+        user_id: usize,
+        reject_add_request: bool
+    },
+    GetFriendList,
+    /// Looks up any user, including ones the bot isn't friends with.
+    GetStrangerInfo {
+        user_id: usize
+    },
+    /// Reacts to a message with an emoji, given by its QQ face id.
+    SetMsgEmojiLike {
+        message_id: usize,
+        emoji_id: usize
     }
 }
 
 pub enum APIResponse {
+    /// The request succeeded, with nothing to return. Endpoints without
+    /// a message id answer with this rather than a [APIResponse::SendMsgResult].
+    Ok,
     SendMsgResult {
         /// If the message has been sent seccessfully.
         success: bool,
@@ -107,14 +232,14 @@ impl From<serde_json::Error> for APIError {
     }
 }
 
-impl ToString for APIError {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for APIError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            APIError::APIError(err) => err.to_string(),
-            APIError::ChannelReceive(err) => err.to_string(),
-            APIError::ChannelSend(err) => err.to_string(),
-            APIError::MismatchedResponse => "Mismatched Response".to_string(),
-            APIError::RequestFailed => "Request Failed".to_string()
+            APIError::APIError(err) => write!(f, "{}", err),
+            APIError::ChannelReceive(err) => write!(f, "{}", err),
+            APIError::ChannelSend(err) => write!(f, "{}", err),
+            APIError::MismatchedResponse => write!(f, "Mismatched Response"),
+            APIError::RequestFailed => write!(f, "Request Failed")
         }
     }
 }
@@ -173,6 +298,11 @@ impl APIWrapper {
         }
     }
 
+    /// Sends `content` as a single markdown segment.
+    pub async fn send_group_markdown(&self, group_id: usize, content: &str) -> Result<usize, APIError> {
+        self.send_group_msg(group_id, vec![MessageArrayItem::Markdown(content.to_string())]).await
+    }
+
     pub async fn send_private_text(&self, user_id: usize, content: &str) -> Result<usize, APIError> {
         let (tx, rx) = oneshot::channel();
         self.sender.send(APIRequest {
@@ -220,4 +350,107 @@ impl APIWrapper {
             _ => Err(APIError::MismatchedResponse)
         }
     }
-}
\ No newline at end of file
+
+    pub async fn set_group_card(&self, group_id: usize, user_id: usize, card: &str) -> Result<(), APIError> {
+        let (tx, rx) = oneshot::channel();
+        self.sender.send(APIRequest {
+            api: API::SetGroupCard { group_id, user_id, card: card.to_string() },
+            resp_tx: tx
+        })?;
+        match rx.await? {
+            APIResponse::Ok => Ok(()),
+            APIResponse::Error { message } => Err(APIError::APIError(message)),
+            _ => Err(APIError::MismatchedResponse)
+        }
+    }
+
+    /// Mutes `user_id` for `duration_secs`, or unmutes them if it's `0`.
+    pub async fn set_group_ban(&self, group_id: usize, user_id: usize, duration_secs: u64) -> Result<(), APIError> {
+        let (tx, rx) = oneshot::channel();
+        self.sender.send(APIRequest {
+            api: API::SetGroupBan { group_id, user_id, duration_secs },
+            resp_tx: tx
+        })?;
+        match rx.await? {
+            APIResponse::Ok => Ok(()),
+            APIResponse::Error { message } => Err(APIError::APIError(message)),
+            _ => Err(APIError::MismatchedResponse)
+        }
+    }
+
+    pub async fn set_group_kick(&self, group_id: usize, user_id: usize, reject_add_request: bool) -> Result<(), APIError> {
+        let (tx, rx) = oneshot::channel();
+        self.sender.send(APIRequest {
+            api: API::SetGroupKick { group_id, user_id, reject_add_request },
+            resp_tx: tx
+        })?;
+        match rx.await? {
+            APIResponse::Ok => Ok(()),
+            APIResponse::Error { message } => Err(APIError::APIError(message)),
+            _ => Err(APIError::MismatchedResponse)
+        }
+    }
+
+    pub async fn get_friend_list(&self) -> Result<Vec<User>, APIError> {
+        let (tx, rx) = oneshot::channel();
+        self.sender.send(APIRequest {
+            api: API::GetFriendList,
+            resp_tx: tx
+        })?;
+        match rx.await? {
+            APIResponse::MemberList(users) => Ok(users),
+            APIResponse::Error { message } => Err(APIError::APIError(message)),
+            _ => Err(APIError::MismatchedResponse)
+        }
+    }
+
+    pub async fn get_stranger_info(&self, user_id: usize) -> Result<User, APIError> {
+        let (tx, rx) = oneshot::channel();
+        self.sender.send(APIRequest {
+            api: API::GetStrangerInfo { user_id },
+            resp_tx: tx
+        })?;
+        match rx.await? {
+            APIResponse::UserInfo(user) => Ok(user),
+            APIResponse::Error { message } => Err(APIError::APIError(message)),
+            _ => Err(APIError::MismatchedResponse)
+        }
+    }
+
+    pub async fn react(&self, message_id: usize, emoji_id: usize) -> Result<(), APIError> {
+        let (tx, rx) = oneshot::channel();
+        self.sender.send(APIRequest {
+            api: API::SetMsgEmojiLike { message_id, emoji_id },
+            resp_tx: tx
+        })?;
+        match rx.await? {
+            APIResponse::Ok => Ok(()),
+            APIResponse::Error { message } => Err(APIError::APIError(message)),
+            _ => Err(APIError::MismatchedResponse)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::unbounded_channel;
+    use crate::{logging::LoggerProvider, objects::Message};
+
+    #[tokio::test]
+    async fn test_drop_repeated_messages() {
+        let _logger_thread = LoggerProvider::init();
+
+        let (tx, mut rx) = unbounded_channel();
+        let mut events = EventSender::new(tx);
+        let message = Message::builder().message_id(42).group(1).sender(2).text("你好").build();
+
+        events.send(Event::Message(message.clone())).unwrap();
+        events.send(Event::Message(message.clone())).unwrap();
+        events.send(Event::Message(Message { message_id: 43, ..message })).unwrap();
+
+        assert!(matches!(rx.try_recv(), Ok(Event::Message(msg)) if msg.message_id == 42));
+        assert!(matches!(rx.try_recv(), Ok(Event::Message(msg)) if msg.message_id == 43), "重复投递的消息应被丢弃");
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -1,14 +1,15 @@
-use std::{collections::VecDeque, sync::{Arc, Mutex}, time::Duration};
+use std::sync::atomic::Ordering;
 
-use tokio::{select, time::sleep};
+use tokio::{select, sync::mpsc::UnboundedSender, time::sleep};
 use websockets::{Frame, WebSocket, WebSocketError};
 
-use crate::{CONFIG, adapters::Listener, SELFID, adapters::napcat::objects::{MetaEvent, NapCatPost}, get_logger, objects::Event};
+use crate::{CONNECTED, SELFID, Status, adapters::{EventSender, Listener, napcat::objects::{MetaEvent, NapCatPost}, reconnect_delay}, get_config, get_logger, objects::Event};
 
 
 pub struct ListenerNapCat {
-    pub events: Arc<Mutex<VecDeque<Event>>>,
-    pub status: Arc<Mutex<bool>>
+    /// Events are sent to whoever holds the receiver, usually `main`.
+    pub events: EventSender,
+    pub status: Status
 }
 
 
@@ -16,14 +17,16 @@ impl Listener for ListenerNapCat {
     async fn run(&mut self) {
         let logger = get_logger();
         
-        while *self.status.lock().unwrap() {
-            match self.connect_websocket().await {
+        while self.status.running() {
+            let result = self.connect_websocket().await;
+            CONNECTED.store(false, Ordering::Relaxed);
+            match result {
                 Ok(_) => {},
                 Err(e) => {
                     logger.info(&format!("WebSocket connection failed: {}", e));
-                    if *self.status.lock().unwrap() {
-                        sleep(Duration::from_secs(3)).await;
-                        logger.info("Trying to reconnect...");
+                    select! {
+                        _ = sleep(reconnect_delay()) => logger.info("Trying to reconnect..."),
+                        _ = self.status.stopped() => {}
                     }
                 }
             }
@@ -33,42 +36,42 @@ impl Listener for ListenerNapCat {
 
 impl ListenerNapCat {
 
-    pub fn init(status: Arc<Mutex<bool>>) -> Self {
-        Self { events: Arc::new(Mutex::new(VecDeque::new())), status }
+    pub fn init(status: Status, events: UnboundedSender<Event>) -> Self {
+        Self { events: EventSender::new(events), status }
     }
 
     async fn connect_websocket(&mut self) -> Result<(), WebSocketError> {
         let mut ws = WebSocket::builder()
-            .add_header("Authorization", &format!("Bearer {}", &CONFIG.network.login_token))
-            .connect(&CONFIG.network.websocket)
+            .add_header("Authorization", &format!("Bearer {}", &get_config().network.login_token))
+            .connect(&get_config().network.websocket)
             .await?;
                 
-        while *self.status.lock().unwrap() {
+        loop {
             select! {
                 result = ws.receive() => {
                     self.handle_websocket_frame(result?);
                 }
-                _ = sleep(Duration::from_millis(100)) => {
-                    if !*self.status.lock().unwrap() {
-                        let _ = ws.close(None);
-                        return Ok(());
-                    }
+                _ = self.status.stopped() => {
+                    let _ = ws.close(None);
+                    return Ok(());
                 }
             }
         }
-        Ok(())
     }
     
     fn handle_websocket_frame(&mut self, frame: Frame) {
         let logger = get_logger();
         match frame {
             Frame::Text { payload, .. } => {
+                logger.trace(&payload);
                 match serde_json::from_str::<NapCatPost>(&payload) {
                     Ok(NapCatPost::MetaEvent(meta_event)) => {
                         self.handle_meta_event(meta_event);
                     },
                     Ok(NapCatPost::Event(event)) => {
-                        self.events.lock().unwrap().push_back(event);
+                        if self.events.send(event).is_err() {
+                            logger.warn("Event dropped: no one is receiving events.");
+                        }
                     },
                     Ok(NapCatPost::Other) => {},
                     Err(err) => logger.info(&err.to_string()),
@@ -86,12 +89,14 @@ impl ListenerNapCat {
         let logger = get_logger();
         match meta_event {
             MetaEvent::Heartbeat { online, good } => {
+                CONNECTED.store(online, Ordering::Relaxed);
                 if !online { logger.info("[Heartbeat] Bot is not online."); }
                 if !good { logger.info("[Heartbeat] Bot is not good."); }
             },
             MetaEvent::Connected { self_id } => {
                 logger.info(&format!("Bot Connected: {}", self_id));
                 SELFID.lock().unwrap().replace(self_id);
+                CONNECTED.store(true, Ordering::Relaxed);
             }
         }
     }
@@ -1,15 +1,15 @@
-use std::{sync::{Arc, Mutex}};
-use tokio::{spawn, task::JoinHandle};
+use tokio::{spawn, sync::mpsc::UnboundedSender, task::JoinHandle};
 
-use crate::adapters::{Listener, napcat::{listener::ListenerNapCat, poster::PosterNapCat}};
+use crate::{Status, adapters::{Listener, napcat::{listener::ListenerNapCat, poster::PosterNapCat}}, objects::Event};
 
 pub mod poster;
 pub mod listener;
 pub mod objects;
 
-pub fn get_pair() -> (ListenerNapCat, PosterNapCat) {
-    let status = Arc::new(Mutex::new(true));
-    (ListenerNapCat::init(status.clone()), PosterNapCat::init(status.clone()))
+/// `events` receives every event the listener gets.
+pub fn get_pair(events: UnboundedSender<Event>) -> (ListenerNapCat, PosterNapCat) {
+    let status = Status::new();
+    (ListenerNapCat::init(status.clone(), events), PosterNapCat::init(status.clone()))
 }
 
 pub fn run_pair(mut lis: ListenerNapCat, mut pos: PosterNapCat) -> JoinHandle<()> {
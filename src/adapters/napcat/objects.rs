@@ -1,3 +1,4 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize, de::Error};
 use serde_json::{Map, Value};
 
@@ -63,6 +64,10 @@ impl<'a> Deserialize<'a> for NapCatPost {
             }
             "message" => {
                 let message_id = extract!(map, "message_id", as_u64) as usize;
+                // Unix seconds.
+                let time = extract_optional!(map, "time", as_i64)
+                    .and_then(|secs| DateTime::from_timestamp(secs, 0))
+                    .unwrap_or_else(Utc::now);
 
                 let mut group: Option<Group> = None;
                 let private = match extract!(map, "message_type", as_str).as_str() {
@@ -105,6 +110,7 @@ impl<'a> Deserialize<'a> for NapCatPost {
                                 let mut data = extract!(item, "data", as_object);
                                 match item_type.as_str() {
                                     "text" => array.push(MessageArrayItem::Text(extract!(data, "text", as_str))),
+                                    "markdown" => array.push(MessageArrayItem::Markdown(extract!(data, "content", as_str))),
                                     "face" => array.push(MessageArrayItem::Face(extract!(data, "id", as_u64) as usize)),
                                     "image" => array.push(MessageArrayItem::Image {
                                         summary: extract_optional!(data, "summary", as_str),
@@ -132,7 +138,7 @@ impl<'a> Deserialize<'a> for NapCatPost {
                     "string" => Vec::new(),
                     _ => Vec::new()
                 };
-                NapCatPost::Event(Event::Message(Message { message_id, private, group, sender, raw: raw_message, array: message_array }))
+                NapCatPost::Event(Event::Message(Message { message_id, private, group, sender, raw: raw_message, array: message_array, time }))
             }
             _ => NapCatPost::Other
         };
@@ -1,14 +1,15 @@
-use std::{sync::{Arc, Mutex}, time::Duration};
 use reqwest::Client;
 use serde_json::{Map, Value, json};
-use tokio::{select, sync::mpsc, time::sleep};
+use tokio::{select, sync::{mpsc, oneshot}};
 
-use crate::{CONFIG, POSTER, adapters::{API, APIError, APIReceiver, APIRequest, APIResponse, APIWrapper}, get_logger, objects::MessageArrayItem};
+use crate::{POSTER, Status, adapters::{API, APIError, APIReceiver, APIRequest, APIResponse, APIWrapper}, get_config, get_logger, objects::{MessageArrayItem, Permission, User}};
 
 pub struct PosterNapCat {
     receiver: APIReceiver,
-    pub status: Arc<Mutex<bool>>,
-    client: Client
+    pub status: Status,
+    client: Client,
+    /// Used instead of `network.http` if set, e.g. by tests.
+    pub http_root: Option<String>
 }
 
 macro_rules! extract {
@@ -20,70 +21,62 @@ macro_rules! extract {
 }
 
 impl PosterNapCat {
-    pub fn init(status: Arc<Mutex<bool>>) -> Self {
+    /// Makes the poster the global [POSTER].
+    pub fn init(status: Status) -> Self {
         let (tx, rx) = mpsc::unbounded_channel::<APIRequest>();
         POSTER.lock().unwrap().replace(APIWrapper { sender: tx });
+        Self::new(rx, status)
+    }
+
+    /// A poster serving `receiver` only, leaving the global [POSTER] alone.
+    pub fn new(receiver: APIReceiver, status: Status) -> Self {
         Self {
-            receiver: rx,
+            receiver,
             status: status,
-            client: Client::new()
+            client: Client::new(),
+            http_root: None
         }
     }
 
     pub async fn handle(&self, req: APIRequest) {
         match req.api {
             API::SendGroupMsg { group_id, content } => {
-                match self.post("send_group_msg", json!({
+                self.send_message("send_group_msg", json!({
                     "group_id": group_id,
                     "message":  MessageArrayItem::format_array(content)
-                })).await {
-                    Ok(res) => {
-                        let _ = req.resp_tx.send(APIResponse::from_res(res, |mut map| {
-                            Ok(APIResponse::SendMsgResult {
-                                success: match extract!(map, "status", as_str).as_str() {
-                                    "ok" => true, _ => false
-                                },
-                                message_id: extract!(extract!(map, "data", as_object), "message_id", as_u64) as usize
-                            })
-                        }));
-                    }
-                    Err(err) => {
-                        let _ = req.resp_tx.send(err.into());
-                    }
-                }
+                }), req.resp_tx).await;
             }
             API::SendPrivateMsg { user_id, content } => {
-                match self.post("send_private_msg", json!({
+                self.send_message("send_private_msg", json!({
                     "user_id": user_id,
                     "message":  MessageArrayItem::format_array(content)
-                })).await {
-                    Ok(res) => {
-                        let _ = req.resp_tx.send(APIResponse::from_res(res, |mut map| {
-                            Ok(APIResponse::SendMsgResult {
-                                success: match extract!(map, "status", as_str).as_str() {
-                                    "ok" => true, _ => false
-                                },
-                                message_id: extract!(extract!(map, "data", as_object), "message_id", as_u64) as usize
-                            })
-                        }));
-                    }
-                    Err(err) => {
-                        let _ = req.resp_tx.send(err.into());
-                    }
-                }
+                }), req.resp_tx).await;
             }
             API::SendGroupText { group_id, content } => {
-                match self.post("send_group_msg", json!({
+                self.send_message("send_group_msg", json!({
                     "group_id": group_id,
                     "message": content
+                }), req.resp_tx).await;
+            }
+            API::SendPrivateText { user_id, content } => {
+                self.send_message("send_private_msg", json!({
+                    "user_id": user_id,
+                    "message": content
+                }), req.resp_tx).await;
+            }
+            API::UploadGroupFile { group_id, file, name } => {
+                match self.post("upload_group_file", json!({
+                    "group_id": group_id,
+                    "file": file,
+                    "name": name
                 })).await {
                     Ok(res) => {
                         let _ = req.resp_tx.send(APIResponse::from_res(res, |mut map| {
-                            Ok(APIResponse::SendMsgResult {
+                            Ok(APIResponse::UploadFileResult {
                                 success: match extract!(map, "status", as_str).as_str() {
                                     "ok" => true, _ => false
                                 },
-                                message_id: extract!(extract!(map, "data", as_object), "message_id", as_u64) as usize
+                                file_id: extract!(extract!(map, "data", as_object), "file_id", as_str)
                             })
                         }));
                     }
@@ -92,18 +85,19 @@ impl PosterNapCat {
                     }
                 }
             }
-            API::SendPrivateText { user_id, content } => {
-                match self.post("send_private_msg", json!({
+            API::UploadPrivateFile { user_id, file, name } => {
+                match self.post("upload_private_file", json!({
                     "user_id": user_id,
-                    "message": content
+                    "file": file,
+                    "name": name
                 })).await {
                     Ok(res) => {
                         let _ = req.resp_tx.send(APIResponse::from_res(res, |mut map| {
-                            Ok(APIResponse::SendMsgResult {
+                            Ok(APIResponse::UploadFileResult {
                                 success: match extract!(map, "status", as_str).as_str() {
                                     "ok" => true, _ => false
                                 },
-                                message_id: extract!(extract!(map, "data", as_object), "message_id", as_u64) as usize
+                                file_id: extract!(extract!(map, "data", as_object), "file_id", as_str)
                             })
                         }));
                     }
@@ -112,20 +106,55 @@ impl PosterNapCat {
                     }
                 }
             }
-            API::UploadGroupFile { group_id, file, name } => {
-                match self.post("upload_group_file", json!({
+            API::SetGroupCard { group_id, user_id, card } => {
+                self.call_ok("set_group_card", json!({
                     "group_id": group_id,
-                    "file": file,
-                    "name": name
-                })).await {
+                    "user_id": user_id,
+                    "card": card
+                }), req.resp_tx).await;
+            }
+            API::SetGroupBan { group_id, user_id, duration_secs } => {
+                self.call_ok("set_group_ban", json!({
+                    "group_id": group_id,
+                    "user_id": user_id,
+                    "duration": duration_secs
+                }), req.resp_tx).await;
+            }
+            API::SetGroupKick { group_id, user_id, reject_add_request } => {
+                self.call_ok("set_group_kick", json!({
+                    "group_id": group_id,
+                    "user_id": user_id,
+                    "reject_add_request": reject_add_request
+                }), req.resp_tx).await;
+            }
+            API::SetMsgEmojiLike { message_id, emoji_id } => {
+                self.call_ok("set_msg_emoji_like", json!({
+                    "message_id": message_id,
+                    "emoji_id": emoji_id
+                }), req.resp_tx).await;
+            }
+            API::GetFriendList => {
+                match self.post("get_friend_list", json!({})).await {
                     Ok(res) => {
                         let _ = req.resp_tx.send(APIResponse::from_res(res, |mut map| {
-                            Ok(APIResponse::UploadFileResult {
-                                success: match extract!(map, "status", as_str).as_str() {
-                                    "ok" => true, _ => false
-                                },
-                                file_id: extract!(extract!(map, "data", as_object), "file_id", as_str)
-                            })
+                            if extract!(map, "status", as_str) != "ok" {
+                                return Err(APIError::RequestFailed);
+                            }
+                            let mut friends = Vec::new();
+                            for friend in extract!(map, "data", as_array) {
+                                let Value::Object(mut friend) = friend else {
+                                    return Err(APIError::APIError("Malformed friend".to_string()));
+                                };
+                                // The remark is the name the bot's account gave the friend.
+                                let remark = optional_str(&mut friend, "remark");
+                                friends.push(User {
+                                    user_id: extract!(friend, "user_id", as_u64) as usize,
+                                    nickname: remark.or_else(|| optional_str(&mut friend, "nickname")),
+                                    card: None,
+                                    role: Permission::Normal
+                                });
+                            }
+                            Ok(APIResponse::MemberList(friends))
                         }));
                     }
                     Err(err) => {
@@ -133,20 +162,22 @@ impl PosterNapCat {
                     }
                 }
             }
-            API::UploadPrivateFile { user_id, file, name } => {
-                match self.post("upload_private_file", json!({
-                    "user_id": user_id,
-                    "file": file,
-                    "name": name
+            API::GetStrangerInfo { user_id } => {
+                match self.post("get_stranger_info", json!({
+                    "user_id": user_id
                 })).await {
                     Ok(res) => {
                         let _ = req.resp_tx.send(APIResponse::from_res(res, |mut map| {
-                            Ok(APIResponse::UploadFileResult {
-                                success: match extract!(map, "status", as_str).as_str() {
-                                    "ok" => true, _ => false
-                                },
-                                file_id: extract!(extract!(map, "data", as_object), "file_id", as_str)
-                            })
+                            if extract!(map, "status", as_str) != "ok" {
+                                return Err(APIError::RequestFailed);
+                            }
+                            let mut data = extract!(map, "data", as_object);
+                            Ok(APIResponse::UserInfo(User {
+                                user_id: extract!(data, "user_id", as_u64) as usize,
+                                nickname: optional_str(&mut data, "nickname"),
+                                card: None,
+                                role: Permission::Normal
+                            }))
                         }));
                     }
                     Err(err) => {
@@ -157,31 +188,67 @@ impl PosterNapCat {
         }
     }
 
+    /// Posts a message to `endpoint` and answers `resp_tx` with its id.
+    async fn send_message(&self, endpoint: &str, payload: Value, resp_tx: oneshot::Sender<APIResponse>) {
+        match self.post(endpoint, payload).await {
+            Ok(res) => {
+                let _ = resp_tx.send(APIResponse::from_res(res, |mut map| {
+                    Ok(APIResponse::SendMsgResult {
+                        success: match extract!(map, "status", as_str).as_str() {
+                            "ok" => true, _ => false
+                        },
+                        message_id: extract!(extract!(map, "data", as_object), "message_id", as_u64) as usize
+                    })
+                }));
+            }
+            Err(err) => {
+                let _ = resp_tx.send(err.into());
+            }
+        }
+    }
+
+    /// Posts to an `endpoint` that returns nothing, answering `resp_tx`
+    /// with [APIResponse::Ok] if its status is `ok`.
+    async fn call_ok(&self, endpoint: &str, payload: Value, resp_tx: oneshot::Sender<APIResponse>) {
+        match self.post(endpoint, payload).await {
+            Ok(res) => {
+                let _ = resp_tx.send(APIResponse::from_res(res, |mut map| {
+                    match extract!(map, "status", as_str).as_str() {
+                        "ok" => Ok(APIResponse::Ok),
+                        _ => Err(APIError::RequestFailed)
+                    }
+                }));
+            }
+            Err(err) => {
+                let _ = resp_tx.send(err.into());
+            }
+        }
+    }
+
     pub async fn run(&mut self) {
         loop {
             select! {
                 Some(req) = self.receiver.recv() => {
                     self.handle(req).await;
                 }
-                _ = sleep(Duration::from_millis(100)) => {
-                    if !*self.status.lock().unwrap() {
-                        *POSTER.lock().unwrap() = None;
-                        return;
-                    }
+                _ = self.status.stopped() => {
+                    *POSTER.lock().unwrap() = None;
+                    return;
                 }
             }
         }
     }
 
     async fn post(&self, end: &str, json: Value) -> Result<Map<String, Value>, APIError> {
+        let root = self.http_root.clone().unwrap_or_else(|| get_config().network.http.clone());
         let res = self.client
-            .post(format!("{}/{}", CONFIG.network.http.trim_matches('/'), end))
-            .header("Authorization", format!("Bearer {}", &CONFIG.network.login_token))
+            .post(format!("{}/{}", root.trim_matches('/'), end))
+            .header("Authorization", format!("Bearer {}", &get_config().network.login_token))
             .json(&json)
             .send().await?
             .text().await?;
         
-        get_logger().debug(&res);
+        get_logger().trace(&format!("[{}] {}", end, res));
         let res_body = serde_json::from_str::<Map<String, Value>>(&res)?;
         Ok(res_body)
     }
@@ -203,6 +270,12 @@ impl MessageArrayItem {
                     "text": content
                 }
             }),
+            MessageArrayItem::Markdown(content) => json!({
+                "type": "markdown",
+                "data": {
+                    "content": content
+                }
+            }),
             MessageArrayItem::Face(face_id) => json!({
                 "type": "face",
                 "data": {
@@ -240,6 +313,13 @@ impl MessageArrayItem {
     }
 }
 
+/// Takes a string field out of `map`, treating an empty one as absent.
+fn optional_str(map: &mut Map<String, Value>, key: &str) -> Option<String> {
+    map.remove(key)
+        .and_then(|v| v.as_str().map(|s| s.to_string()))
+        .filter(|s| !s.is_empty())
+}
+
 impl APIResponse {
     pub fn from_res(map: Map<String, Value>, f: fn(Map<String, Value>) -> Result<APIResponse, APIError>) -> APIResponse {
         match (f)(map) {
@@ -247,4 +327,70 @@ impl APIResponse {
             Err(err) => err.into()
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::LoggerProvider;
+
+    async fn request(poster: &PosterNapCat, api: API) -> APIResponse {
+        let (tx, rx) = oneshot::channel();
+        poster.handle(APIRequest { api, resp_tx: tx }).await;
+        rx.await.expect("应返回响应")
+    }
+
+    #[tokio::test]
+    async fn test_send_message() {
+        let _logger_thread = LoggerProvider::init();
+
+        let mut server = mockito::Server::new_async().await;
+        let group = server.mock("POST", "/send_group_msg")
+            .match_body(mockito::Matcher::PartialJson(json!({ "group_id": 1, "message": "你好" })))
+            .with_body(json!({ "status": "ok", "data": { "message_id": 42 } }).to_string())
+            .create_async().await;
+        let private = server.mock("POST", "/send_private_msg")
+            .match_body(mockito::Matcher::PartialJson(json!({ "user_id": 2 })))
+            .with_body(json!({ "status": "failed", "data": { "message_id": 0 } }).to_string())
+            .create_async().await;
+
+        let (_tx, rx) = mpsc::unbounded_channel();
+        let mut poster = PosterNapCat::new(rx, Status::new());
+        poster.http_root = Some(server.url());
+
+        let res = request(&poster, API::SendGroupText { group_id: 1, content: "你好".to_string() }).await;
+        assert!(matches!(res, APIResponse::SendMsgResult { success: true, message_id: 42 }), "应解析出消息 id");
+
+        let res = request(&poster, API::SendPrivateMsg {
+            user_id: 2,
+            content: vec![MessageArrayItem::Text("你好".to_string())]
+        }).await;
+        assert!(matches!(res, APIResponse::SendMsgResult { success: false, .. }), "status 不为 ok 时应视为失败");
+
+        group.assert_async().await;
+        private.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_call_ok() {
+        let _logger_thread = LoggerProvider::init();
+
+        let mut server = mockito::Server::new_async().await;
+        let _ban = server.mock("POST", "/set_group_ban")
+            .with_body(json!({ "status": "ok", "data": null }).to_string())
+            .create_async().await;
+        let _kick = server.mock("POST", "/set_group_kick")
+            .with_body(json!({ "status": "failed", "data": null }).to_string())
+            .create_async().await;
+
+        let (_tx, rx) = mpsc::unbounded_channel();
+        let mut poster = PosterNapCat::new(rx, Status::new());
+        poster.http_root = Some(server.url());
+
+        let res = request(&poster, API::SetGroupBan { group_id: 1, user_id: 2, duration_secs: 60 }).await;
+        assert!(matches!(res, APIResponse::Ok), "没有返回值的接口成功时应返回 Ok");
+
+        let res = request(&poster, API::SetGroupKick { group_id: 1, user_id: 2, reject_add_request: false }).await;
+        assert!(matches!(res, APIResponse::Error { .. }), "失败时应返回错误");
+    }
+}
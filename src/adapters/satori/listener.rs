@@ -0,0 +1,117 @@
+use std::{sync::{Arc, Mutex, atomic::Ordering}, time::Duration};
+
+use serde_json::json;
+use tokio::{select, sync::mpsc::UnboundedSender, time::{Instant, sleep, sleep_until}};
+use websockets::{Frame, WebSocket, WebSocketError};
+
+use crate::{CONNECTED, SELFID, Status, adapters::{EventSender, Listener, reconnect_delay, satori::objects::{Login, SatoriPost, op}}, get_config, get_logger, objects::Event};
+
+/// Satori closes connections that haven't pinged for a while.
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+
+pub struct ListenerSatori {
+    /// Events are sent to whoever holds the receiver, usually `main`.
+    pub events: EventSender,
+    pub status: Status,
+    /// Shared with the poster, which needs it to call the API.
+    login: Arc<Mutex<Option<Login>>>,
+    /// The last event's sequence number, to resume from after reconnecting.
+    sn: Option<u64>
+}
+
+impl Listener for ListenerSatori {
+    async fn run(&mut self) {
+        let logger = get_logger();
+
+        while self.status.running() {
+            let result = self.connect_websocket().await;
+            CONNECTED.store(false, Ordering::Relaxed);
+            match result {
+                Ok(_) => {},
+                Err(e) => {
+                    logger.info(&format!("WebSocket connection failed: {}", e));
+                    select! {
+                        _ = sleep(reconnect_delay()) => logger.info("Trying to reconnect..."),
+                        _ = self.status.stopped() => {}
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl ListenerSatori {
+
+    pub fn init(status: Status, events: UnboundedSender<Event>, login: Arc<Mutex<Option<Login>>>) -> Self {
+        Self { events: EventSender::new(events), status, login, sn: None }
+    }
+
+    async fn connect_websocket(&mut self) -> Result<(), WebSocketError> {
+        let mut ws = WebSocket::builder()
+            .connect(&get_config().network.websocket)
+            .await?;
+
+        let mut identify = json!({ "token": &get_config().network.login_token });
+        if let Some(sn) = self.sn {
+            identify["sn"] = json!(sn);
+        }
+        ws.send_text(json!({ "op": op::IDENTIFY, "body": identify }).to_string()).await?;
+
+        let mut next_ping = Instant::now() + PING_INTERVAL;
+        loop {
+            select! {
+                result = ws.receive() => {
+                    self.handle_websocket_frame(result?);
+                }
+                _ = sleep_until(next_ping) => {
+                    ws.send_text(json!({ "op": op::PING }).to_string()).await?;
+                    next_ping = Instant::now() + PING_INTERVAL;
+                }
+                _ = self.status.stopped() => {
+                    let _ = ws.close(None);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn handle_websocket_frame(&mut self, frame: Frame) {
+        let logger = get_logger();
+        match frame {
+            Frame::Text { payload, .. } => {
+                logger.trace(&payload);
+                match serde_json::from_str::<SatoriPost>(&payload) {
+                    Ok(SatoriPost::Ready(login)) => {
+                        logger.info(&format!("Bot Connected: {} on {}", login.self_id, login.platform));
+                        match login.self_id.parse::<usize>() {
+                            Ok(self_id) => { SELFID.lock().unwrap().replace(self_id); },
+                            Err(_) => logger.warn(&format!("Self id {} is not numeric, mentions won't be noticed.", login.self_id))
+                        }
+                        self.login.lock().unwrap().replace(login);
+                        CONNECTED.store(true, Ordering::Relaxed);
+                    },
+                    Ok(SatoriPost::Event { sn, event }) => {
+                        if sn.is_some() {
+                            self.sn = sn;
+                        }
+                        // Satori reports the bot's own messages too.
+                        let Event::Message(msg) = &event;
+                        if Some(msg.sender.user_id) == *SELFID.lock().unwrap() {
+                            return;
+                        }
+                        if self.events.send(event).is_err() {
+                            logger.warn("Event dropped: no one is receiving events.");
+                        }
+                    },
+                    Ok(SatoriPost::Pong) | Ok(SatoriPost::Other) => {},
+                    Err(err) => logger.info(&err.to_string()),
+                }
+            },
+            Frame::Close { payload } => {
+                let (code, msg) = payload.unwrap_or((0u16, "Unknown".to_string()));
+                logger.info(&format!("WebSocket closed: {} - {}", code, msg));
+            },
+            _ => {}
+        }
+    }
+}
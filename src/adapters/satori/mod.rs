@@ -0,0 +1,28 @@
+use std::sync::{Arc, Mutex};
+use tokio::{spawn, sync::mpsc::UnboundedSender, task::JoinHandle};
+
+use crate::{Status, adapters::{Listener, satori::{listener::ListenerSatori, poster::PosterSatori}}, objects::Event};
+
+pub mod poster;
+pub mod listener;
+pub mod objects;
+
+/// `events` receives every event the listener gets.
+pub fn get_pair(events: UnboundedSender<Event>) -> (ListenerSatori, PosterSatori) {
+    let status = Status::new();
+    let login = Arc::new(Mutex::new(None));
+    (ListenerSatori::init(status.clone(), events, login.clone()), PosterSatori::init(status.clone(), login))
+}
+
+pub fn run_pair(mut lis: ListenerSatori, mut pos: PosterSatori) -> JoinHandle<()> {
+    spawn(async move {
+        let lis_handle = spawn(async move {
+            lis.run().await
+        });
+        let pos_handle = spawn(async move {
+            pos.run().await
+        });
+        lis_handle.await.unwrap();
+        pos_handle.await.unwrap();
+    })
+}
@@ -0,0 +1,291 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, de::Error};
+use serde_json::{Map, Value};
+
+use crate::objects::{Event, Group, Message, MessageArrayItem, Permission, User};
+
+/// Opcodes of Satori signaling.
+pub mod op {
+    pub const EVENT: u64 = 0;
+    pub const PING: u64 = 1;
+    pub const PONG: u64 = 2;
+    pub const IDENTIFY: u64 = 3;
+    pub const READY: u64 = 4;
+}
+
+/// The account the bot is logged in as.
+#[derive(Debug, Clone)]
+pub struct Login {
+    pub platform: String,
+    pub self_id: String
+}
+
+#[derive(Debug)]
+pub enum SatoriPost {
+    Ready(Login),
+    Event {
+        /// Sequence number, sent back when resuming after a reconnect.
+        sn: Option<u64>,
+        event: Event
+    },
+    Pong,
+    Other
+}
+
+macro_rules! extract {
+    ($map:expr, $key:literal, $extractor:ident) => {
+        $map.remove($key)
+            .and_then(|v| v.$extractor().map(|o| o.to_owned()))
+            .ok_or_else(|| serde::de::Error::missing_field($key))?
+    };
+}
+
+macro_rules! extract_optional {
+    ($map:expr, $key:literal, $extractor:ident) => {
+        $map.remove($key)
+            .and_then(|v| v.$extractor().map(|o| o.to_owned()))
+    };
+}
+
+/// Satori ids are strings. The crate uses numbers, so other ids are rejected.
+fn parse_id<E: Error>(id: &str) -> Result<usize, E> {
+    id.parse::<usize>().map_err(|_| E::invalid_value(serde::de::Unexpected::Str(id), &"numeric id"))
+}
+
+impl<'a> Deserialize<'a> for SatoriPost {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: serde::Deserializer<'a> {
+        let mut map = Map::<String, Value>::deserialize(deserializer)?;
+
+        let post = match extract!(map, "op", as_u64) {
+            op::READY => {
+                let mut body = extract!(map, "body", as_object);
+                let Some(Value::Object(mut login)) = extract!(body, "logins", as_array).into_iter().next() else {
+                    return Err(D::Error::custom("no login is ready"));
+                };
+                let platform = extract!(login, "platform", as_str);
+                let self_id = match extract_optional!(login, "user", as_object) {
+                    Some(mut user) => extract!(user, "id", as_str),
+                    None => extract!(login, "self_id", as_str)
+                };
+                SatoriPost::Ready(Login { platform, self_id })
+            }
+            op::PONG => SatoriPost::Pong,
+            op::EVENT => {
+                let mut body = extract!(map, "body", as_object);
+                let sn = extract_optional!(body, "sn", as_u64).or(extract_optional!(body, "id", as_u64));
+                match extract!(body, "type", as_str).as_str() {
+                    "message-created" => SatoriPost::Event { sn, event: Event::Message(message(body)?) },
+                    _ => SatoriPost::Other
+                }
+            }
+            _ => SatoriPost::Other
+        };
+        Ok(post)
+    }
+}
+
+/// Builds a [Message] from the body of a `message-created` event.
+fn message<E: Error>(mut body: Map<String, Value>) -> Result<Message, E> {
+    // Unix milliseconds.
+    let time = extract_optional!(body, "timestamp", as_i64)
+        .and_then(DateTime::from_timestamp_millis)
+        .unwrap_or_else(Utc::now);
+    let mut channel = extract!(body, "channel", as_object);
+    // Channel type 1 is a direct message.
+    let private = extract_optional!(channel, "type", as_u64) == Some(1);
+
+    let group = match (private, extract_optional!(body, "guild", as_object)) {
+        (false, Some(mut guild)) => Some(Group {
+            group_id: parse_id(&extract!(guild, "id", as_str))?,
+            group_name: extract_optional!(guild, "name", as_str)
+        }),
+        (false, None) => Some(Group {
+            group_id: parse_id(&extract!(channel, "id", as_str))?,
+            group_name: extract_optional!(channel, "name", as_str)
+        }),
+        (true, _) => None
+    };
+
+    let mut user = extract!(body, "user", as_object);
+    let card = extract_optional!(body, "member", as_object)
+        .and_then(|mut member| extract_optional!(member, "nick", as_str))
+        .filter(|nick| !nick.is_empty());
+    let sender = User {
+        user_id: parse_id(&extract!(user, "id", as_str))?,
+        nickname: extract_optional!(user, "nick", as_str)
+            .or(extract_optional!(user, "name", as_str))
+            .filter(|name| !name.is_empty()),
+        card,
+        role: Permission::Normal
+    };
+
+    let mut message = extract!(body, "message", as_object);
+    let message_id = extract!(message, "id", as_str).parse::<usize>().unwrap_or(0);
+    let array = parse_content(&extract!(message, "content", as_str));
+    let raw = array.iter().map(|item| match item {
+        MessageArrayItem::Text(text) => text.clone(),
+        MessageArrayItem::At(user_id) => format!("@{}", user_id),
+        MessageArrayItem::Image { .. } => "[图片]".to_string(),
+        _ => String::new()
+    }).collect::<String>();
+
+    Ok(Message { message_id, private, group, sender, raw, array, time })
+}
+
+/// Parses Satori message elements. Text inside unknown elements is kept,
+/// the elements themselves are dropped.
+pub fn parse_content(content: &str) -> Vec<MessageArrayItem> {
+    let mut array = Vec::new();
+    let mut text = String::new();
+    let mut rest = content;
+
+    while let Some(start) = rest.find('<') {
+        text.push_str(&unescape(&rest[..start]));
+        let Some(len) = rest[start..].find('>') else {
+            rest = &rest[start..];
+            break;
+        };
+        let tag = &rest[start + 1..start + len];
+        rest = &rest[start + len + 1..];
+
+        if tag.starts_with('/') {
+            continue;
+        }
+        let (name, attrs) = parse_tag(tag);
+        let item = match name {
+            "at" => match attrs.iter().find(|(key, _)| *key == "type") {
+                Some((_, kind)) if kind == "all" => Some(MessageArrayItem::At(0)),
+                _ => attrs.iter()
+                    .find(|(key, _)| *key == "id")
+                    .and_then(|(_, id)| id.parse::<usize>().ok())
+                    .map(MessageArrayItem::At)
+            },
+            "img" | "image" => attrs.iter().find(|(key, _)| *key == "src").map(|(_, src)| MessageArrayItem::Image {
+                summary: None,
+                file: None,
+                url: src.clone(),
+                file_size: None
+            }),
+            _ => None
+        };
+        if let Some(item) = item {
+            if !text.is_empty() {
+                array.push(MessageArrayItem::Text(std::mem::take(&mut text)));
+            }
+            array.push(item);
+        }
+    }
+
+    text.push_str(&unescape(rest));
+    if !text.is_empty() {
+        array.push(MessageArrayItem::Text(text));
+    }
+    array
+}
+
+/// Splits `name key="value" .../` into the name and its attributes.
+fn parse_tag(tag: &str) -> (&str, Vec<(&str, String)>) {
+    let tag = tag.trim_end_matches('/').trim();
+    let (name, mut rest) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+    let mut attrs = Vec::new();
+
+    while let Some(eq) = rest.find('=') {
+        let key = rest[..eq].trim();
+        let value = rest[eq + 1..].trim_start();
+        let Some(quote) = value.chars().next().filter(|c| *c == '"' || *c == '\'') else { break };
+        let Some(end) = value[1..].find(quote) else { break };
+        attrs.push((key, unescape(&value[1..end + 1])));
+        rest = &value[end + 2..];
+    }
+    (name, attrs)
+}
+
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&amp;", "&")
+}
+
+impl MessageArrayItem {
+    /// Formats `array` as Satori message elements.
+    /// Satori has no markdown element, so markdown is sent as text.
+    pub fn format_elements(array: Vec<MessageArrayItem>) -> String {
+        array.iter().map(|item| match item {
+            MessageArrayItem::Text(text) | MessageArrayItem::Markdown(text) => escape(text),
+            MessageArrayItem::Face(face_id) => format!("<face id=\"{}\"/>", face_id),
+            MessageArrayItem::At(0) => "<at type=\"all\"/>".to_string(),
+            MessageArrayItem::At(user_id) => format!("<at id=\"{}\"/>", user_id),
+            MessageArrayItem::Image { url, .. } => format!("<img src=\"{}\"/>", escape(url))
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_content() {
+        let array = parse_content("你好 <at id=\"42\"/> a &lt; b<img src=\"http://x/1.png?a=1&amp;b=2\"/>");
+
+        assert!(matches!(&array[0], MessageArrayItem::Text(text) if text == "你好 "));
+        assert!(matches!(array[1], MessageArrayItem::At(42)), "应解析出 @");
+        assert!(matches!(&array[2], MessageArrayItem::Text(text) if text == " a < b"), "转义字符应被还原");
+        assert!(matches!(&array[3], MessageArrayItem::Image { url, .. } if url == "http://x/1.png?a=1&b=2"));
+    }
+
+    #[test]
+    fn test_deserialize_post() -> anyhow::Result<()> {
+        let ready: SatoriPost = serde_json::from_str(
+            r#"{"op": 4, "body": {"logins": [{"platform": "qq", "user": {"id": "10000"}}]}}"#
+        )?;
+        assert!(matches!(&ready, SatoriPost::Ready(login) if login.platform == "qq" && login.self_id == "10000"));
+
+        let event: SatoriPost = serde_json::from_str(r#"{"op": 0, "body": {
+            "sn": 3,
+            "type": "message-created",
+            "timestamp": 1700000000000,
+            "channel": {"id": "123", "type": 0},
+            "guild": {"id": "123", "name": "测试群"},
+            "user": {"id": "42", "name": "lin"},
+            "member": {"nick": "小林"},
+            "message": {"id": "7", "content": "<at type=\"all\"/> 开会"}
+        }}"#)?;
+        let SatoriPost::Event { sn, event: Event::Message(message) } = event else {
+            panic!("应解析出消息事件");
+        };
+        assert_eq!(sn, Some(3));
+        assert!(!message.private);
+        assert_eq!(message.group.map(|group| group.group_id), Some(123));
+        assert_eq!(message.sender.user_id, 42);
+        assert_eq!(message.sender.card.as_deref(), Some("小林"));
+        assert!(matches!(message.array[0], MessageArrayItem::At(0)), "@全体成员应解析为 At(0)");
+
+        let other: SatoriPost = serde_json::from_str(r#"{"op": 0, "body": {"type": "guild-added"}}"#)?;
+        assert!(matches!(other, SatoriPost::Other));
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_round_trip() {
+        let content = MessageArrayItem::format_elements(vec![
+            MessageArrayItem::At(7),
+            MessageArrayItem::Text(" <b>&".to_string())
+        ]);
+        assert_eq!(content, "<at id=\"7\"/> &lt;b&gt;&amp;");
+
+        let array = parse_content(&content);
+        assert!(matches!(array[0], MessageArrayItem::At(7)));
+        assert!(matches!(&array[1], MessageArrayItem::Text(text) if text == " <b>&"), "文本应原样还原");
+    }
+}
@@ -0,0 +1,180 @@
+use std::sync::{Arc, Mutex};
+use reqwest::Client;
+use serde_json::{Value, json};
+use tokio::{select, sync::mpsc};
+
+use crate::{POSTER, Status, adapters::{API, APIError, APIReceiver, APIRequest, APIResponse, APIWrapper, satori::objects::{Login, escape}}, get_config, get_logger, objects::{MessageArrayItem, Permission, User}};
+
+pub struct PosterSatori {
+    receiver: APIReceiver,
+    pub status: Status,
+    client: Client,
+    /// Filled by the listener once the server is ready.
+    login: Arc<Mutex<Option<Login>>>
+}
+
+impl PosterSatori {
+    pub fn init(status: Status, login: Arc<Mutex<Option<Login>>>) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel::<APIRequest>();
+        POSTER.lock().unwrap().replace(APIWrapper { sender: tx });
+        Self {
+            receiver: rx,
+            status: status,
+            client: Client::new(),
+            login
+        }
+    }
+
+    pub async fn handle(&self, req: APIRequest) {
+        let res = match req.api {
+            API::SendGroupMsg { group_id, content } => {
+                self.send(group_id.to_string(), MessageArrayItem::format_elements(content)).await
+            }
+            API::SendPrivateMsg { user_id, content } => {
+                self.send_private(user_id, MessageArrayItem::format_elements(content)).await
+            }
+            API::SendGroupText { group_id, content } => {
+                self.send(group_id.to_string(), escape(&content)).await
+            }
+            API::SendPrivateText { user_id, content } => {
+                self.send_private(user_id, escape(&content)).await
+            }
+            // Files are sent as `<file>` elements, their message ids standing in for file ids.
+            API::UploadGroupFile { group_id, file, name } => {
+                self.send(group_id.to_string(), file_element(&file, &name)).await
+                    .map(upload_result)
+            }
+            API::UploadPrivateFile { user_id, file, name } => {
+                self.send_private(user_id, file_element(&file, &name)).await
+                    .map(upload_result)
+            }
+            API::SetGroupCard { .. } => Err(APIError::APIError("Satori can't set group cards".to_string())),
+            // `reaction.create` needs the channel, which the request doesn't carry.
+            API::SetMsgEmojiLike { .. } => Err(APIError::APIError("Satori can't react without a channel".to_string())),
+            API::SetGroupBan { group_id, user_id, duration_secs } => {
+                self.call("guild.member.mute", json!({
+                    "guild_id": group_id.to_string(),
+                    "user_id": user_id.to_string(),
+                    "duration": duration_secs.saturating_mul(1000)
+                })).await.map(|_| APIResponse::Ok)
+            }
+            API::SetGroupKick { group_id, user_id, reject_add_request } => {
+                self.call("guild.member.kick", json!({
+                    "guild_id": group_id.to_string(),
+                    "user_id": user_id.to_string(),
+                    "permanent": reject_add_request
+                })).await.map(|_| APIResponse::Ok)
+            }
+            API::GetFriendList => self.friends().await.map(APIResponse::MemberList),
+            API::GetStrangerInfo { user_id } => {
+                self.call("user.get", json!({ "user_id": user_id.to_string() })).await
+                    .and_then(|user| Ok(APIResponse::UserInfo(to_user(user)?)))
+            }
+        };
+        let _ = req.resp_tx.send(res.unwrap_or_else(|err| err.into()));
+    }
+
+    pub async fn run(&mut self) {
+        loop {
+            select! {
+                Some(req) = self.receiver.recv() => {
+                    self.handle(req).await;
+                }
+                _ = self.status.stopped() => {
+                    *POSTER.lock().unwrap() = None;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Sends `content` to a channel. Group channels share the group's id.
+    async fn send(&self, channel_id: String, content: String) -> Result<APIResponse, APIError> {
+        let res = self.call("message.create", json!({
+            "channel_id": channel_id,
+            "content": content
+        })).await?;
+        // One message is returned for every part the content was split into.
+        let message_id = res.as_array()
+            .and_then(|messages| messages.first())
+            .and_then(|message| message["id"].as_str())
+            .and_then(|id| id.parse::<usize>().ok())
+            .unwrap_or(0);
+        Ok(APIResponse::SendMsgResult { success: true, message_id })
+    }
+
+    async fn send_private(&self, user_id: usize, content: String) -> Result<APIResponse, APIError> {
+        let channel = self.call("user.channel.create", json!({ "user_id": user_id.to_string() })).await?;
+        let channel_id = channel["id"].as_str()
+            .ok_or_else(|| APIError::APIError("Missing field: id".to_string()))?;
+        self.send(channel_id.to_string(), content).await
+    }
+
+    /// Every friend, following the pages of `friend.list`.
+    async fn friends(&self) -> Result<Vec<User>, APIError> {
+        let mut friends = Vec::new();
+        let mut next: Option<String> = None;
+        loop {
+            let mut page = self.call("friend.list", json!({ "next": next })).await?;
+            if let Value::Array(users) = page["data"].take() {
+                for user in users {
+                    friends.push(to_user(user)?);
+                }
+            }
+            next = page["next"].as_str().map(|next| next.to_string());
+            if next.is_none() {
+                return Ok(friends);
+            }
+        }
+    }
+
+    async fn call(&self, method: &str, json: Value) -> Result<Value, APIError> {
+        let Some(login) = self.login.lock().unwrap().clone() else {
+            return Err(APIError::APIError("Satori is not ready".to_string()));
+        };
+        // Older servers read the `X-` headers.
+        let res = self.client
+            .post(format!("{}/{}", get_config().network.http.trim_matches('/'), method))
+            .header("Authorization", format!("Bearer {}", &get_config().network.login_token))
+            .header("Satori-Platform", &login.platform)
+            .header("Satori-User-ID", &login.self_id)
+            .header("X-Platform", &login.platform)
+            .header("X-Self-ID", &login.self_id)
+            .json(&json)
+            .send().await?
+            .error_for_status()?
+            .text().await?;
+
+        get_logger().trace(&format!("[{}] {}", method, res));
+        if res.trim().is_empty() {
+            return Ok(Value::Null);
+        }
+        Ok(serde_json::from_str::<Value>(&res)?)
+    }
+}
+
+fn file_element(file: &str, name: &str) -> String {
+    format!("<file src=\"{}\" title=\"{}\"/>", escape(file), escape(name))
+}
+
+fn upload_result(res: APIResponse) -> APIResponse {
+    match res {
+        APIResponse::SendMsgResult { success, message_id } => APIResponse::UploadFileResult {
+            success,
+            file_id: message_id.to_string()
+        },
+        other => other
+    }
+}
+
+/// Converts a Satori user. `nick`, if set, is preferred over `name`.
+fn to_user(user: Value) -> Result<User, APIError> {
+    let user_id = user["id"].as_str()
+        .and_then(|id| id.parse::<usize>().ok())
+        .ok_or_else(|| APIError::APIError("Missing field: id".to_string()))?;
+    let nickname = ["nick", "name"].iter()
+        .filter_map(|key| user[key].as_str())
+        .find(|name| !name.is_empty())
+        .map(|name| name.to_string());
+    Ok(User { user_id, nickname, card: None, role: Permission::Normal })
+}
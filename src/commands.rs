@@ -1,15 +1,643 @@
-use crate::objects::Message;
+use std::{collections::HashMap, sync::{Arc, Mutex, atomic::Ordering}};
 
+use async_trait::async_trait;
+use tokio::{spawn, sync::{mpsc::UnboundedSender, oneshot}};
 
+use crate::{CONNECTED, START_TIME, adapters::APIWrapper, config::{Config, PermissionConfig}, get_config, get_logger, get_poster, memory::{AliasesMapping, ExcludedScopes, MemoryService, Scope}, objects::{Message, MessageArrayItem, Permission}, thinking::{FlushRequest, ThinkerStats}, tools::{ToolMetrics, ToolRegistry}};
 
-pub async fn run_cmds(msg: Message) -> bool {
+/// Commands are invoked as `#name args...`.
+pub const COMMAND_PREFIX: &str = "#";
 
-    let mut flag = false;
+pub enum CommandOutcome {
+    /// The command ran.
+    Done,
+    /// The command couldn't run. The reason is sent back to the sender.
+    Failed(String)
+}
 
-    if msg.on_command("#echo") {
-        msg.quick_send_text(&msg.joint_args()).await;
-        flag = true;
+#[async_trait]
+pub trait Command: Send + Sync {
+    /// The name without [COMMAND_PREFIX].
+    fn name(&self) -> &str;
+    fn description(&self) -> &str;
+    /// The least permission level needed to run the command.
+    fn permission(&self) -> i32;
+    /// Replies and other requests go through `poster`.
+    async fn run(&self, msg: &Message, poster: &APIWrapper) -> CommandOutcome;
+}
+
+pub struct CommandRegistry {
+    commands: HashMap<String, Arc<dyn Command>>
+}
+
+impl CommandRegistry {
+    pub fn new() -> Self {
+        Self { commands: HashMap::new() }
+    }
+
+    pub fn register<T: Command + 'static>(&mut self, command: T) {
+        self.commands.insert(command.name().to_string(), Arc::new(command));
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn Command>> {
+        self.commands.get(name).cloned()
+    }
+
+    /// `(name, description)` of the registered commands, sorted by name.
+    pub fn describe(&self) -> Vec<(String, String)> {
+        let mut commands = self.commands.values()
+            .map(|command| (command.name().to_string(), command.description().to_string()))
+            .collect::<Vec<(String, String)>>();
+        commands.sort();
+        commands
+    }
+
+    /// Finds the command named by the first token of `msg`.
+    pub fn find(&self, msg: &Message) -> Option<Arc<dyn Command>> {
+        let first = msg.to_cmd_array().pop_front()?;
+        self.get(first.strip_prefix(COMMAND_PREFIX)?)
+    }
+
+    /// Runs the command in `msg`, if any. Returns whether a command matched.
+    pub async fn run_cmds(&self, msg: &Message) -> bool {
+        self.run_cmds_with(msg, &get_config().permission, &get_poster()).await
+    }
+
+    /// Like [CommandRegistry::run_cmds], checking permissions against `permission`
+    /// and replying through `poster`.
+    pub async fn run_cmds_with(&self, msg: &Message, permission: &PermissionConfig, poster: &APIWrapper) -> bool {
+        let Some(command) = self.find(msg) else { return false };
+
+        if msg.permission_level(permission) < command.permission() {
+            get_logger().info(&format!(
+                "User {} has no permission to run command: {}", msg.sender.user_id, command.name()
+            ));
+            msg.quick_send_text_with(poster, "权限不足。").await;
+            return true;
+        }
+
+        get_logger().debug(&format!("Running command: {}", command.name()));
+        if let CommandOutcome::Failed(reason) = command.run(msg, poster).await {
+            msg.quick_send_text_with(poster, &reason).await;
+        }
+
+        true
+    }
+}
+
+pub struct EchoCommand;
+
+#[async_trait]
+impl Command for EchoCommand {
+    fn name(&self) -> &str {
+        "echo"
+    }
+
+    fn description(&self) -> &str {
+        "原样发送参数"
+    }
+
+    fn permission(&self) -> i32 {
+        0
+    }
+
+    async fn run(&self, msg: &Message, poster: &APIWrapper) -> CommandOutcome {
+        msg.quick_send_text_with(poster, &msg.joint_args()).await;
+        CommandOutcome::Done
+    }
+}
+
+pub struct StatusCommand {
+    pub stats: Arc<ThinkerStats>,
+    pub mem_service: Arc<MemoryService>
+}
+
+#[async_trait]
+impl Command for StatusCommand {
+    fn name(&self) -> &str {
+        "status"
+    }
+
+    fn description(&self) -> &str {
+        "查看运行状态"
+    }
+
+    fn permission(&self) -> i32 {
+        0
+    }
+
+    async fn run(&self, msg: &Message, poster: &APIWrapper) -> CommandOutcome {
+        let uptime = START_TIME.elapsed().as_secs();
+        let lines = [
+            format!("运行时间：{}天{}小时{}分", uptime / 86400, uptime % 86400 / 3600, uptime % 3600 / 60),
+            format!("适配器：{}", if CONNECTED.load(Ordering::Relaxed) { "已连接" } else { "未连接" }),
+            format!("活跃频道：{}", self.stats.channels.load(Ordering::Relaxed)),
+            format!("待整理消息：{}", self.stats.pending.load(Ordering::Relaxed)),
+            format!("数据库：{}", if self.mem_service.ping().await { "正常" } else { "无法连接" })
+        ];
+        msg.quick_send_text_with(poster, &lines.join("\n")).await;
+        CommandOutcome::Done
+    }
+}
+
+/// Reloads the config file, see [Config::reload].
+pub struct ReloadCommand;
+
+#[async_trait]
+impl Command for ReloadCommand {
+    fn name(&self) -> &str {
+        "reload"
+    }
+
+    fn description(&self) -> &str {
+        "重新加载配置文件"
+    }
+
+    fn permission(&self) -> i32 {
+        Permission::Admin.level()
+    }
+
+    async fn run(&self, msg: &Message, poster: &APIWrapper) -> CommandOutcome {
+        match Config::reload() {
+            Ok(restart) => {
+                get_logger().info("Config reloaded.");
+                let mut reply = "配置已重新加载。".to_string();
+                if !restart.is_empty() {
+                    reply += &format!("\n以下配置需要重启后生效：{}", restart.join(", "));
+                }
+                msg.quick_send_text_with(poster, &reply).await;
+                CommandOutcome::Done
+            }
+            Err(err) => {
+                get_logger().error(&format!("Error reloading config: {}", err));
+                CommandOutcome::Failed(format!("重新加载配置失败：{}", err))
+            }
+        }
+    }
+}
+
+/// Turns memory extraction on or off for the chat it's sent in.
+/// Memories already stored are still searched.
+pub struct MemoryCommand {
+    pub excluded: Arc<Mutex<ExcludedScopes>>
+}
+
+#[async_trait]
+impl Command for MemoryCommand {
+    fn name(&self) -> &str {
+        "memory"
+    }
+
+    fn description(&self) -> &str {
+        "开启或关闭当前聊天的记忆总结：memory on|off"
+    }
+
+    fn permission(&self) -> i32 {
+        Permission::Admin.level()
+    }
+
+    async fn run(&self, msg: &Message, poster: &APIWrapper) -> CommandOutcome {
+        let scope = Scope::from(msg);
+        let (reply, changed) = {
+            let mut excluded = self.excluded.lock().unwrap();
+            match msg.arg_at::<String>(0).as_deref() {
+                Some("off") => ("已关闭当前聊天的记忆总结。".to_string(), excluded.insert(scope)),
+                Some("on") => ("已开启当前聊天的记忆总结。".to_string(), excluded.remove(&scope)),
+                _ => (format!(
+                    "当前聊天的记忆总结已{}。用法：{}memory on|off",
+                    if excluded.contains(&scope) { "关闭" } else { "开启" }, COMMAND_PREFIX
+                ), false)
+            }
+        };
+
+        if changed {
+            if let Err(err) = self.excluded.lock().unwrap().save() {
+                get_logger().error(&format!("Error saving memory exclusions: {}", err));
+                return CommandOutcome::Failed("保存设置失败。".to_string());
+            }
+        }
+
+        msg.quick_send_text_with(poster, &reply).await;
+        CommandOutcome::Done
+    }
+}
+
+/// Lists, adds or removes the aliases of a user.
+pub struct AliasCommand {
+    pub map: Arc<Mutex<AliasesMapping>>
+}
+
+impl AliasCommand {
+    const USAGE: &'static str = "alias <QQ号> | alias add|del <QQ号> <别称>";
+
+    /// Carries out `args` on `map`. Returns the reply and whether `map` changed,
+    /// or the usage if `args` don't fit.
+    fn apply(map: &mut AliasesMapping, args: &[String]) -> Result<(String, bool), String> {
+        let usage = || format!("用法：{}{}", COMMAND_PREFIX, Self::USAGE);
+        let parse_id = |id: &String| id.parse::<usize>().map_err(|_| usage());
+
+        let (user_id, reply, changed) = match args {
+            [user_id] => (parse_id(user_id)?, String::new(), false),
+            [action, user_id, alias] if action == "add" => {
+                let user_id = parse_id(user_id)?;
+                let changed = map.insert(user_id, alias.trim());
+                (user_id, if changed { "已添加别称。" } else { "该别称已存在。" }.to_string(), changed)
+            }
+            [action, user_id, alias] if action == "del" => {
+                let user_id = parse_id(user_id)?;
+                let changed = map.remove(user_id, alias.trim());
+                (user_id, if changed { "已删除别称。" } else { "没有这个别称。" }.to_string(), changed)
+            }
+            _ => return Err(usage())
+        };
+
+        let mut aliases = map.get(user_id)
+            .map(|aliases| aliases.iter().cloned().collect::<Vec<String>>())
+            .unwrap_or_default();
+        aliases.sort();
+        let list = if aliases.is_empty() {
+            format!("{} 没有别称。", user_id)
+        } else {
+            format!("{} 的别称：{}", user_id, aliases.join("、"))
+        };
+        Ok((reply + &list, changed))
+    }
+}
+
+#[async_trait]
+impl Command for AliasCommand {
+    fn name(&self) -> &str {
+        "alias"
+    }
+
+    fn description(&self) -> &str {
+        "查看或修改用户的别称：alias <QQ号> | alias add|del <QQ号> <别称>"
+    }
+
+    fn permission(&self) -> i32 {
+        Permission::Admin.level()
+    }
+
+    async fn run(&self, msg: &Message, poster: &APIWrapper) -> CommandOutcome {
+        let result = Self::apply(&mut self.map.lock().unwrap(), &msg.quoted_args());
+        let reply = match result {
+            Ok((reply, changed)) => {
+                if changed {
+                    if let Err(err) = self.map.lock().unwrap().save() {
+                        get_logger().error(&format!("Error saving aliases: {}", err));
+                        return CommandOutcome::Failed("保存别称失败。".to_string());
+                    }
+                }
+                reply
+            }
+            Err(usage) => return CommandOutcome::Failed(usage)
+        };
+
+        msg.quick_send_text_with(poster, &reply).await;
+        CommandOutcome::Done
+    }
+}
+
+/// Summarizes every pending message into memories right away,
+/// regardless of `memory.doze_threshold`.
+pub struct DozeCommand {
+    pub flush: UnboundedSender<FlushRequest>
+}
+
+#[async_trait]
+impl Command for DozeCommand {
+    fn name(&self) -> &str {
+        "doze"
+    }
+
+    fn description(&self) -> &str {
+        "立即总结所有待处理的消息为记忆"
+    }
+
+    fn permission(&self) -> i32 {
+        Permission::Admin.level()
+    }
+
+    async fn run(&self, msg: &Message, poster: &APIWrapper) -> CommandOutcome {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        if self.flush.send(reply_tx).is_err() {
+            return CommandOutcome::Failed("思考线程未运行。".to_string());
+        }
+        msg.quick_send_text_with(poster, "正在总结记忆……").await;
+
+        // Summarizing takes a while, and other commands shouldn't wait for it.
+        let (msg, poster) = (msg.clone(), poster.clone());
+        spawn(async move {
+            let reply = match reply_rx.await {
+                Ok(Ok(report)) => format!("已总结 {} 个聊天的 {} 条消息。", report.scopes, report.messages),
+                Ok(Err(err)) => {
+                    get_logger().error(&format!("Error flushing memories: {}", err));
+                    "记忆总结失败。".to_string()
+                }
+                Err(_) => "思考线程未运行。".to_string()
+            };
+            msg.quick_send_text_with(&poster, &reply).await;
+        });
+        CommandOutcome::Done
     }
+}
 
-    flag
-}
\ No newline at end of file
+/// The first member mentioned in `msg`, not counting @all.
+fn mentioned_user(msg: &Message) -> Option<usize> {
+    msg.array.iter().find_map(|item| match item {
+        MessageArrayItem::At(user_id) if *user_id != 0 => Some(*user_id),
+        _ => None
+    })
+}
+
+/// The member a moderation command targets: the first one mentioned,
+/// or the first argument as a user id.
+fn target_user(msg: &Message) -> Option<usize> {
+    mentioned_user(msg).or_else(|| msg.arg_at(0))
+}
+
+/// The longest mute QQ allows, 30 days.
+const MAX_MUTE_SECS: u64 = 30 * 24 * 60 * 60;
+
+/// Mutes a group member for some minutes. `0` minutes unmutes them.
+pub struct MuteCommand;
+
+#[async_trait]
+impl Command for MuteCommand {
+    fn name(&self) -> &str {
+        "mute"
+    }
+
+    fn description(&self) -> &str {
+        "禁言群成员，0 分钟为解除禁言：mute <QQ号|@成员> <分钟>"
+    }
+
+    fn permission(&self) -> i32 {
+        Permission::Admin.level()
+    }
+
+    async fn run(&self, msg: &Message, poster: &APIWrapper) -> CommandOutcome {
+        let Some(group) = &msg.group else {
+            return CommandOutcome::Failed("该命令只能在群聊中使用。".to_string());
+        };
+        // A mention may or may not show up in the arguments, so the minutes are
+        // the last argument after one, and the second one after a user id.
+        let args = msg.parse_args();
+        let minutes = match mentioned_user(msg) {
+            Some(_) => args.positional.last(),
+            None => args.positional.get(1)
+        };
+        let minutes = minutes.and_then(|minutes| minutes.parse::<u64>().ok());
+        let secs = minutes.and_then(|minutes| minutes.checked_mul(60)).filter(|secs| *secs <= MAX_MUTE_SECS);
+        let (Some(user_id), Some(minutes), Some(secs)) = (target_user(msg), minutes, secs) else {
+            return CommandOutcome::Failed(format!(
+                "用法：{}mute <QQ号|@成员> <分钟>，最多 {} 分钟", COMMAND_PREFIX, MAX_MUTE_SECS / 60
+            ));
+        };
+
+        if let Err(err) = poster.set_group_ban(group.group_id, user_id, secs).await {
+            get_logger().error(&format!("Error muting {} in group {}: {}", user_id, group.group_id, err));
+            return CommandOutcome::Failed("禁言失败。".to_string());
+        }
+
+        let reply = if minutes == 0 {
+            format!("已解除 {} 的禁言。", user_id)
+        } else {
+            format!("已禁言 {} {} 分钟。", user_id, minutes)
+        };
+        msg.quick_send_text_with(poster, &reply).await;
+        CommandOutcome::Done
+    }
+}
+
+/// Removes a member from the group. `--block` also rejects their later join requests.
+pub struct KickCommand;
+
+#[async_trait]
+impl Command for KickCommand {
+    fn name(&self) -> &str {
+        "kick"
+    }
+
+    fn description(&self) -> &str {
+        "将成员移出群聊，--block 拒绝其再次加群：kick <QQ号|@成员> [--block]"
+    }
+
+    fn permission(&self) -> i32 {
+        Permission::Admin.level()
+    }
+
+    async fn run(&self, msg: &Message, poster: &APIWrapper) -> CommandOutcome {
+        let Some(group) = &msg.group else {
+            return CommandOutcome::Failed("该命令只能在群聊中使用。".to_string());
+        };
+        let Some(user_id) = target_user(msg) else {
+            return CommandOutcome::Failed(format!("用法：{}kick <QQ号|@成员> [--block]", COMMAND_PREFIX));
+        };
+        let block = msg.parse_args().flag("block");
+
+        if let Err(err) = poster.set_group_kick(group.group_id, user_id, block).await {
+            get_logger().error(&format!("Error kicking {} from group {}: {}", user_id, group.group_id, err));
+            return CommandOutcome::Failed("移出失败。".to_string());
+        }
+
+        msg.quick_send_text_with(poster, &format!("已将 {} 移出群聊。", user_id)).await;
+        CommandOutcome::Done
+    }
+}
+
+/// Shows how often each tool has been called and failed.
+pub struct ToolsCommand {
+    pub metrics: Arc<ToolMetrics>
+}
+
+#[async_trait]
+impl Command for ToolsCommand {
+    fn name(&self) -> &str {
+        "tools"
+    }
+
+    fn description(&self) -> &str {
+        "查看工具的调用次数和失败次数"
+    }
+
+    fn permission(&self) -> i32 {
+        0
+    }
+
+    async fn run(&self, msg: &Message, poster: &APIWrapper) -> CommandOutcome {
+        let stats = self.metrics.snapshot();
+        if stats.is_empty() {
+            msg.quick_send_text_with(poster, "还没有调用过任何工具。").await;
+            return CommandOutcome::Done;
+        }
+
+        let lines = stats.iter()
+            .map(|(name, stats)| format!("{}：调用 {} 次，失败 {} 次", name, stats.calls, stats.failures))
+            .collect::<Vec<String>>();
+        msg.quick_send_text_with(poster, &lines.join("\n")).await;
+        CommandOutcome::Done
+    }
+}
+
+/// Lists commands and the tools the model can use.
+/// Both lists are taken when the command is created.
+pub struct HelpCommand {
+    commands: Vec<(String, String)>,
+    tools: Vec<(String, String)>
+}
+
+impl HelpCommand {
+    pub fn new(commands: &CommandRegistry, tools: &ToolRegistry) -> Self {
+        let mut help = Self { commands: commands.describe(), tools: tools.describe() };
+        help.commands.push((help.name().to_string(), help.description().to_string()));
+        help.commands.sort();
+        help
+    }
+}
+
+#[async_trait]
+impl Command for HelpCommand {
+    fn name(&self) -> &str {
+        "help"
+    }
+
+    fn description(&self) -> &str {
+        "列出可用的命令和工具"
+    }
+
+    fn permission(&self) -> i32 {
+        0
+    }
+
+    async fn run(&self, msg: &Message, poster: &APIWrapper) -> CommandOutcome {
+        let mut lines = vec!["命令：".to_string()];
+        lines.extend(self.commands.iter().map(|(name, description)| {
+            format!("{}{} - {}", COMMAND_PREFIX, name, description)
+        }));
+        lines.push("".to_string());
+        lines.push("工具：".to_string());
+        lines.extend(self.tools.iter().map(|(name, description)| format!("{} - {}", name, description)));
+
+        msg.quick_send_text_with(poster, &lines.join("\n")).await;
+        CommandOutcome::Done
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+    use crate::{adapters::{API, mock::MockPoster}, logging::LoggerProvider, memory::DozeReport};
+
+    struct AdminOnlyCommand {
+        ran: Arc<AtomicBool>
+    }
+
+    #[async_trait]
+    impl Command for AdminOnlyCommand {
+        fn name(&self) -> &str { "admin_only" }
+        fn description(&self) -> &str { "仅管理员可用" }
+        fn permission(&self) -> i32 { 10 }
+        async fn run(&self, _msg: &Message, _poster: &APIWrapper) -> CommandOutcome {
+            self.ran.store(true, Ordering::SeqCst);
+            CommandOutcome::Done
+        }
+    }
+
+    fn command_message(user_id: usize, raw: &str) -> Message {
+        Message::builder().sender(user_id).group(1).text(raw).build()
+    }
+
+    #[tokio::test]
+    async fn test_admin_only_command() {
+        let _logger_thread = LoggerProvider::init_with(|| Arc::new(Config::default()));
+
+        let (poster, requests) = MockPoster::install();
+
+        let ran = Arc::new(AtomicBool::new(false));
+        let mut commands = CommandRegistry::new();
+        commands.register(AdminOnlyCommand { ran: ran.clone() });
+
+        let permission = PermissionConfig {
+            admins: vec!["1".to_string()],
+            ..Default::default()
+        };
+
+        assert!(commands.run_cmds_with(&command_message(2, "#admin_only"), &permission, &poster).await, "命令应被匹配");
+        assert!(!ran.load(Ordering::SeqCst), "普通用户不应能执行管理员命令");
+        assert!(matches!(
+            requests.lock().unwrap().last(),
+            Some(API::SendGroupText { group_id: 1, content }) if content == "权限不足。"
+        ), "应提示权限不足");
+
+        assert!(commands.run_cmds_with(&command_message(1, "#admin_only"), &permission, &poster).await, "命令应被匹配");
+        assert!(ran.load(Ordering::SeqCst), "管理员应能执行管理员命令");
+
+        assert!(!commands.run_cmds_with(&command_message(1, "#unknown"), &permission, &poster).await, "未注册的命令不应被匹配");
+    }
+
+    #[test]
+    fn test_alias_apply() {
+        let mut map = AliasesMapping::default();
+        assert!(!map.is_dirty());
+        let args = |raw: &str| command_message(1, raw).quoted_args();
+
+        let (reply, changed) = AliasCommand::apply(&mut map, &args("#alias add 7 \"小 七\"")).unwrap();
+        assert!(changed, "新别称应被添加");
+        assert!(map.is_dirty(), "修改后应标记为未保存");
+        assert_eq!(reply, "已添加别称。7 的别称：小 七");
+
+        assert!(AliasCommand::apply(&mut map, &args("#alias add 7 小 七")).is_err(), "多余的参数应被拒绝");
+
+        let (reply, changed) = AliasCommand::apply(&mut map, &args("#alias del 7 \"小 七\"")).unwrap();
+        assert!(changed, "别称应被删除");
+        assert_eq!(reply, "已删除别称。7 没有别称。");
+
+        assert_eq!(AliasCommand::apply(&mut map, &args("#alias 7")).unwrap(), ("7 没有别称。".to_string(), false));
+        assert!(AliasCommand::apply(&mut map, &args("#alias add abc x")).is_err(), "无效的QQ号应提示用法");
+    }
+
+    #[tokio::test]
+    async fn test_doze_reports() {
+        let _logger_thread = LoggerProvider::init_with(|| Arc::new(Config::default()));
+        let (poster, requests) = MockPoster::install();
+
+        let (flush, mut flush_requests) = tokio::sync::mpsc::unbounded_channel::<FlushRequest>();
+        spawn(async move {
+            while let Some(reply) = flush_requests.recv().await {
+                let _ = reply.send(Ok(DozeReport { scopes: 2, messages: 7 }));
+            }
+        });
+
+        let outcome = DozeCommand { flush }.run(&command_message(1, "#doze"), &poster).await;
+        assert!(matches!(outcome, CommandOutcome::Done));
+
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(matches!(
+            requests.lock().unwrap().last(),
+            Some(API::SendGroupText { content, .. }) if content == "已总结 2 个聊天的 7 条消息。"
+        ), "应报告总结的聊天数和消息数");
+    }
+
+    #[tokio::test]
+    async fn test_mute_rejects_long_mutes() {
+        let _logger_thread = LoggerProvider::init_with(|| Arc::new(Config::default()));
+        let (poster, requests) = MockPoster::install();
+
+        let outcome = MuteCommand.run(&command_message(1, "#mute 7 307445734561825861"), &poster).await;
+        assert!(matches!(outcome, CommandOutcome::Failed(reply) if reply.contains("最多")), "超出上限的时长应提示用法");
+        assert!(!requests.lock().unwrap().iter().any(|api| matches!(api, API::SetGroupBan { .. })), "不应发出禁言请求");
+    }
+
+    #[test]
+    fn test_target_user() {
+        let mentioned = Message::builder().sender(1).group(1)
+            .text("#mute ").item(MessageArrayItem::At(42)).text(" 10").build();
+        assert_eq!(target_user(&mentioned), Some(42), "应优先使用被@的成员");
+
+        assert_eq!(target_user(&command_message(1, "#mute 7 10")), Some(7));
+        assert_eq!(target_user(&command_message(1, "#mute abc")), None, "无效的QQ号不应被当作目标");
+    }
+}
@@ -1,27 +1,76 @@
-use std::{collections::HashMap, fs, io::{Read, Write}, path::PathBuf, str::FromStr};
+use std::{collections::HashMap, fs, io::Read, path::{Path, PathBuf}, str::FromStr, sync::Arc};
 
 use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
 use smart_default::SmartDefault;
 
+/// The protocol spoken by the bot's backend.
+#[derive(Serialize, Deserialize, SmartDefault, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    /// OneBot 11 as served by NapCat.
+    #[default]
+    NapCat,
+    /// Satori. Only platforms with numeric user and group ids are supported.
+    Satori
+}
+
 #[derive(Serialize, Deserialize, SmartDefault)]
 pub struct NetworkConfig {
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// With Satori, the events endpoint, like `ws://127.0.0.1:5500/v1/events`.
     #[default("ws://127.0.0.1:5500")]
     pub websocket: String,
     #[default("######################")]
     pub login_token: String,
+    /// With Satori, the API root, like `http://127.0.0.1:5500/v1`.
     #[default("http://127.0.0.1:5500/v1")]
-    pub http: String
+    pub http: String,
+    #[serde(default)]
+    pub dedup: DedupConfig
 }
 
+/// Messages a backend delivers again, e.g. after reconnecting, are dropped.
 #[derive(Serialize, Deserialize, SmartDefault)]
+#[serde(default)]
+pub struct DedupConfig {
+    /// How long a message id is remembered, in seconds. `0` turns it off.
+    #[default(300)] pub window_secs: u64,
+    /// Most message ids remembered at once.
+    #[default(1000)] pub capacity: usize
+}
+
+#[derive(Serialize, Deserialize, SmartDefault, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Colored lines with level icons.
+    #[default]
+    Pretty,
+    /// One JSON object per line, with `timestamp`, `level` and `message`.
+    Json
+}
+
+#[derive(Serialize, Deserialize, SmartDefault)]
+#[serde(default)]
 pub struct LoggerConfig {
     #[default(true)] pub info: bool,
     #[default(true)] pub warning: bool,
     #[default(true)] pub error: bool,
     #[default(true)] pub chat: bool,
     #[default(true)] pub debug: bool,
+    /// Raw websocket frames and api responses. Very verbose.
+    #[default(false)] pub trace: bool,
     #[default(false)] pub generate_file: bool,
-    #[default(None)] pub save_path: Option<String>
+    #[default(None)] pub save_path: Option<String>,
+    /// The log file is rotated once larger than this, and at each new day.
+    #[default(10)] pub max_size_mb: u64,
+    /// Rotated log files kept. The oldest are deleted.
+    #[default(7)] pub max_files: usize,
+    pub format: LogFormat,
+    /// Whether console output is colored. Detected from the terminal and
+    /// `NO_COLOR` if not set.
+    #[default(None)] pub color: Option<bool>
 }
 
 #[derive(Serialize, Deserialize, SmartDefault)]
@@ -32,29 +81,771 @@ pub struct PermissionConfig {
     pub other: HashMap<String, i32>
 }
 
+#[derive(Serialize, Deserialize, SmartDefault, Clone)]
+#[serde(default)]
+pub struct RetrievalConfig {
+    /// Max memories returned by a single search.
+    #[default(6)] pub limit: usize,
+    /// Memories farther than this (cosine distance) are ignored
+    /// unless they match by keyword.
+    #[default(0.6)] pub max_cosine_dist: f64,
+    #[default(0.7)] pub vector_weight: f64,
+    #[default(0.3)] pub text_weight: f64,
+    /// In groups, also search the sender's private memories. Off by default,
+    /// since what someone tells the bot in private may not be meant for a group.
+    #[default(false)] pub user_memories: bool
+}
+
+#[derive(Serialize, Deserialize, SmartDefault, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum VectorIndex {
+    /// Needs enough rows to be trained well.
+    #[default]
+    Ivfflat,
+    /// Better recall on small tables, slower to build.
+    Hnsw
+}
+
+impl VectorIndex {
+    /// The index access method in SQL.
+    pub fn method(&self) -> &'static str {
+        match self {
+            VectorIndex::Ivfflat => "ivfflat",
+            VectorIndex::Hnsw => "hnsw"
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, SmartDefault)]
+#[serde(default)]
+pub struct MemoryConfig {
+    pub retrieval: RetrievalConfig,
+    /// How many embeddings of recently seen texts are kept in memory.
+    #[default(256)] pub embed_cache_size: usize,
+    /// Must match the output size of the embedding model.
+    #[default(1024)] pub embed_dim: usize,
+    /// Extension providing `ts_config`, e.g. `pg_jieba` or `zhparser`.
+    #[default(None)] pub ts_extension: Option<String>,
+    /// Falls back to `simple` if the database doesn't have it.
+    #[default("simple")] pub ts_config: String,
+    /// How many messages a scope needs before they are summarized into memories.
+    #[default(50)] pub doze_threshold: usize,
+    /// A scope with fewer messages is still summarized once it has been quiet this long.
+    #[default(86400)] pub doze_max_idle_secs: u64,
+    /// Model used to extract memories from chat, e.g. `deepseek-chat`.
+    #[default("deepseek-chat")] pub extract_model: String,
+    /// Memories extracted while dozing that are at least this similar (cosine)
+    /// to an existing one reinforce it instead of being added. `None` disables it.
+    #[default(Some(0.95))] pub dedup_similarity: Option<f64>,
+    /// Index on the embedding column. Changing it rebuilds the index on startup.
+    pub index: VectorIndex,
+    /// Used when the `DATABASE_URL` environment variable isn't set.
+    #[default(None)] pub database_url: Option<String>,
+    #[default(5)] pub max_connections: u32,
+    /// How long to wait for a free connection before giving up.
+    #[default(5)] pub acquire_timeout_secs: u64
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Trigger {
+    pub keyword: String,
+    pub score: usize
+}
+
+fn default_triggers() -> Vec<Trigger> {
+    [
+        ("rustaris", 40),
+        ("rusta", 40),
+        ("拉斯塔", 40),
+        ("帮", 20),
+        ("?", 20),
+        ("？", 20),
+        ("呢", 20),
+        ("嘛", 20),
+        ("吗", 20),
+        ("!", 10),
+        ("！", 10)
+    ].into_iter().map(|(keyword, score)| Trigger { keyword: keyword.to_string(), score }).collect()
+}
+
+#[derive(Serialize, Deserialize, SmartDefault)]
+#[serde(default)]
+pub struct ThinkerConfig {
+    /// Keywords that add to a message's score when it contains them.
+    #[default(_code = "default_triggers()")] pub triggers: Vec<Trigger>,
+    /// The bot replies to messages scoring at least this much.
+    #[default(50)] pub threshold: usize,
+    /// Added when the bot is @-mentioned.
+    #[default(100)] pub at_bonus: usize,
+    /// Added while the bot is in an ongoing conversation.
+    #[default(30)] pub buff_bonus: usize,
+    /// Max requests to the model for one reply while it keeps calling tools.
+    #[default(5)] pub max_tool_rounds: usize,
+    /// Retries of a chat completion failing with a transient error, e.g. 429 or 5xx.
+    /// Streamed completions are only retried until they start, since part of them may have been sent.
+    #[default(2)] pub retry_attempts: usize,
+    /// Wait before the first retry, doubled for each next one.
+    #[default(1000)] pub retry_backoff_ms: u64,
+    /// Hard cap of a channel's history, in case summarizing keeps failing.
+    #[default(20)] pub history_len: usize,
+    /// Messages older than this are left out of the prompt, in seconds.
+    #[default(1300)] pub history_ttl_secs: u64,
+    /// A channel's history is summarized before the next reply once it holds more turns than this.
+    #[default(16)] pub summarize_threshold: usize,
+    /// How many of the oldest turns are condensed into one summary.
+    #[default(8)] pub summarize_batch: usize,
+    /// Send replies sentence by sentence as they are generated.
+    #[default(false)] pub stream: bool,
+    /// Let the model use markdown, sending replies as markdown segments.
+    /// Only some clients render them. Streamed replies are still sent as text.
+    #[default(false)] pub markdown: bool,
+    /// Mention the sender at the start of replies in groups.
+    #[default(false)] pub at_reply: bool,
+    /// Log replies instead of sending them, and skip tools that aren't
+    /// [read only](crate::tools::Tool::read_only). History is still recorded.
+    #[default(false)] pub dry_run: bool,
+    /// `deepseek-chat` or `deepseek-reasoner`.
+    #[default("deepseek-chat")] pub chat_model: String,
+    /// Sampling temperature in `[0, 2]`. Out-of-range values are clamped.
+    #[default(1.0)] pub temperature: f32,
+    /// Nucleus sampling in `[0, 1]`. Out-of-range values are clamped.
+    #[default(1.0)] pub top_p: f32,
+    /// After replying in a channel, keyword triggers are ignored there for this
+    /// many seconds. @-mentions still get a reply.
+    #[default(10)] pub reply_cooldown_secs: u64,
+    /// Longer replies are split at sentence boundaries into several messages.
+    #[default(1500)] pub max_reply_bytes: usize,
+    /// On exit, how long the thinker gets to finish its current reply
+    /// and flush memories before it is stopped.
+    #[default(30)] pub shutdown_grace_secs: u64,
+    /// Personas by name, used in the groups whose override names them.
+    pub profiles: HashMap<String, PromptProfile>
+}
+
+impl ThinkerConfig {
+    pub fn temperature(&self) -> f32 {
+        self.temperature.clamp(0.0, 2.0)
+    }
+
+    pub fn top_p(&self) -> f32 {
+        self.top_p.clamp(0.0, 1.0)
+    }
+
+    /// The profile `group` names, if any. Names missing from `profiles` are ignored.
+    pub fn profile(&self, group: Option<&GroupOverride>) -> Option<&PromptProfile> {
+        group.and_then(|group| group.profile.as_ref()).and_then(|name| self.profiles.get(name))
+    }
+}
+
+/// A persona the bot takes on in some groups, see [GroupOverride::profile].
+/// Fields left out fall back to `thinker`.
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct PromptProfile {
+    /// Replaces the persona part of the system prompt.
+    /// The rules and the list of tools are kept.
+    pub persona: Option<String>,
+    /// Replaces `thinker.triggers`.
+    pub triggers: Option<Vec<Trigger>>,
+    /// Replaces `thinker.chat_model`.
+    pub chat_model: Option<String>
+}
+
+#[derive(Serialize, Deserialize, SmartDefault)]
+#[serde(default)]
+pub struct ToolsConfig {
+    /// Lets the model search the web. Needs a SearXNG instance at `api.search_root`.
+    #[default(false)] pub web_search: bool,
+    /// Results returned by one web search, at most 10.
+    #[default(5)] pub web_search_results: usize,
+    /// Longer tool results are cut before being sent to the model, in chars.
+    #[default(4000)] pub max_result_chars: usize,
+    /// Captions images in received messages, costing one api call per image.
+    /// Needs `api.vision_root` and `api.vision_key`.
+    #[default(false)] pub vision: bool,
+    #[default("glm-4v-flash")] pub vision_model: String,
+    /// Lets the model react to a message with an emoji instead of replying.
+    #[default(false)] pub react: bool
+}
+
+/// Addresses and keys of the apis the bot calls. Each one may also be set by
+/// the environment variable named below, see [LEGACY_VARS].
+#[derive(Serialize, Deserialize, SmartDefault)]
+#[serde(default)]
+pub struct ApiConfig {
+    /// Overridden by `API_KEY`.
+    #[default(None)] pub deepseek_key: Option<String>,
+    /// Chat completions are sent to `<deepseek_root>/chat/completions`.
+    #[default("https://api.deepseek.com")] pub deepseek_root: String,
+    /// Overridden by `EMBED_API_ROOT`.
+    #[default(None)] pub embed_root: Option<String>,
+    /// Overridden by `EMBED_API_KEY`.
+    #[default(None)] pub embed_key: Option<String>,
+    /// Overridden by `VISION_API_ROOT`.
+    #[default(None)] pub vision_root: Option<String>,
+    /// Overridden by `VISION_API_KEY`.
+    #[default(None)] pub vision_key: Option<String>,
+    /// A SearXNG instance. Overridden by `SEARCH_API_ROOT`.
+    #[default(None)] pub search_root: Option<String>,
+    /// Overridden by `NETEASE_API_ROOT`.
+    #[default("http://192.168.3.38:8099")] pub netease_root: String,
+    /// Overridden by `BILIBILI_API_ROOT`.
+    #[default("https://api.bilibili.com")] pub bilibili_root: String
+}
+
+impl ApiConfig {
+    pub fn deepseek_key(&self) -> anyhow::Result<String> {
+        required("DeepSeek api key", "API_KEY", "api.deepseek_key", &self.deepseek_key)
+    }
+
+    pub fn embed_root(&self) -> anyhow::Result<String> {
+        required("Embedding api root", "EMBED_API_ROOT", "api.embed_root", &self.embed_root)
+    }
+
+    pub fn embed_key(&self) -> anyhow::Result<String> {
+        required("Embedding api key", "EMBED_API_KEY", "api.embed_key", &self.embed_key)
+    }
+
+    pub fn vision_root(&self) -> anyhow::Result<String> {
+        required("Vision api root", "VISION_API_ROOT", "api.vision_root", &self.vision_root)
+    }
+
+    pub fn vision_key(&self) -> anyhow::Result<String> {
+        required("Vision api key", "VISION_API_KEY", "api.vision_key", &self.vision_key)
+    }
+
+    pub fn search_root(&self) -> anyhow::Result<String> {
+        required("Search api root", "SEARCH_API_ROOT", "api.search_root", &self.search_root)
+    }
+
+}
+
+/// `value` if it is set. Fails naming the variable `var` and `field`, either of which sets it.
+/// Warns through the logger, or on stderr while it isn't up, e.g. when loading the config at startup.
+fn warn(msg: &str) {
+    match crate::LOGGER.lock().unwrap().as_ref() {
+        Some(logger) => logger.warn(msg),
+        None => eprintln!("{}", msg)
+    }
+}
+
+fn required(what: &str, var: &str, field: &str, value: &Option<String>) -> anyhow::Result<String> {
+    value.clone()
+        .filter(|value| !value.is_empty())
+        .ok_or_else(|| anyhow::anyhow!("{} is not set, set {} or {}", what, var, field))
+}
+
+/// The probe server, see [crate::health].
+#[derive(Serialize, Deserialize, SmartDefault)]
+#[serde(default)]
+pub struct HealthConfig {
+    /// Serves `/healthz` and `/readyz` over HTTP.
+    #[default(false)] pub enabled: bool,
+    /// The address to listen on, e.g. `127.0.0.1` to only answer local probes.
+    #[default("0.0.0.0")] pub bind: String,
+    #[default(8081)] pub port: u16
+}
+
+/// Settings of one group that differ from the global ones.
+/// Fields left out fall back to the global config.
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct GroupOverride {
+    /// `false` stops the bot from replying in the group.
+    pub enabled: Option<bool>,
+    /// Replaces `thinker.threshold`.
+    pub threshold: Option<usize>,
+    /// `false` stops memories being extracted from the group.
+    pub memory: Option<bool>,
+    /// Names one of `thinker.profiles` to use in the group.
+    pub profile: Option<String>,
+    /// Replaces `memory.retrieval.user_memories`.
+    pub user_memories: Option<bool>
+}
+
 #[derive(Serialize, Deserialize, SmartDefault)]
 pub struct Config {
+    /// How often the main loop checks whether to exit, in seconds. Exiting is
+    /// signalled right away, so this is only a fallback and can be long.
     #[default(0.5)]
     pub heart_beat: f32,
+    /// Dev mode: memories are summarized after every message, and the memories
+    /// table is dropped on startup if destructive actions are allowed.
+    #[serde(default)]
+    pub dev: bool,
+    /// Allows dev mode to drop the memories table. See [Config::destructive_allowed].
+    #[serde(default)]
+    pub dev_allow_destructive: bool,
+    /// Checks the database, the embedding api and the DeepSeek key on startup,
+    /// exiting if any of them fails.
+    #[serde(default)]
+    pub preflight: bool,
     pub network: NetworkConfig,
     pub logger: LoggerConfig,
-    pub permission: PermissionConfig
+    pub permission: PermissionConfig,
+    #[serde(default)]
+    pub memory: MemoryConfig,
+    #[serde(default)]
+    pub thinker: ThinkerConfig,
+    #[serde(default)]
+    pub tools: ToolsConfig,
+    #[serde(default)]
+    pub api: ApiConfig,
+    #[serde(default)]
+    pub health: HealthConfig,
+    /// Overrides keyed by group id. Keys are strings, like in `permission.other`,
+    /// so that every config format can hold them.
+    #[serde(default)]
+    pub groups: HashMap<String, GroupOverride>
+}
+
+/// Prefix of environment variables overriding config fields.
+const ENV_PREFIX: &str = "RUSTARIS_";
+
+/// Environment variables from before [ENV_PREFIX] existed, and the variables
+/// they stand for. Those with the prefix win if both are set.
+const LEGACY_VARS: &[(&str, &str)] = &[
+    ("API_KEY", "RUSTARIS_API__DEEPSEEK_KEY"),
+    ("EMBED_API_ROOT", "RUSTARIS_API__EMBED_ROOT"),
+    ("EMBED_API_KEY", "RUSTARIS_API__EMBED_KEY"),
+    ("VISION_API_ROOT", "RUSTARIS_API__VISION_ROOT"),
+    ("VISION_API_KEY", "RUSTARIS_API__VISION_KEY"),
+    ("SEARCH_API_ROOT", "RUSTARIS_API__SEARCH_ROOT"),
+    ("NETEASE_API_ROOT", "RUSTARIS_API__NETEASE_ROOT"),
+    ("BILIBILI_API_ROOT", "RUSTARIS_API__BILIBILI_ROOT")
+];
+
+/// Fields only read at startup, as JSON pointers. Changing them needs a restart.
+const RESTART_REQUIRED: &[&str] = &[
+    "/network/protocol",
+    "/dev",
+    "/dev_allow_destructive",
+    "/memory",
+    "/thinker/chat_model",
+    "/tools/web_search",
+    "/tools/web_search_results",
+    "/tools/vision",
+    "/tools/vision_model",
+    "/tools/react",
+    "/api",
+    "/health",
+    "/logger/generate_file",
+    "/logger/save_path",
+    "/logger/max_size_mb",
+    "/logger/max_files",
+    "/logger/color"
+];
+
+/// Config files looked for, in this order, unless `RUSTARIS_CONFIG` is set.
+const CONFIG_FILES: &[&str] = &["config.toml", "config.yaml", "config.yml", "config.json"];
+
+#[derive(Clone, Copy)]
+enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml
+}
+
+impl ConfigFormat {
+    fn of(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => ConfigFormat::Toml,
+            Some("yaml" | "yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Json
+        }
+    }
+
+    fn parse(self, text: &str) -> anyhow::Result<Config> {
+        Ok(match self {
+            ConfigFormat::Json => serde_json::from_str(text)?,
+            ConfigFormat::Toml => toml::from_str(text)?,
+            ConfigFormat::Yaml => serde_yaml::from_str(text)?
+        })
+    }
+
+    fn render(self, config: &Config) -> anyhow::Result<String> {
+        Ok(match self {
+            ConfigFormat::Json => serde_json::to_string_pretty(config)?,
+            ConfigFormat::Toml => toml::to_string_pretty(config)?,
+            ConfigFormat::Yaml => serde_yaml::to_string(config)?
+        })
+    }
+}
+
+/// The config file isn't valid in its format, as opposed to unreadable.
+#[derive(Debug)]
+pub struct ParseError(anyhow::Error);
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
 }
+
+impl std::error::Error for ParseError {}
+
 impl Config {
+    /// The config file in use: `RUSTARIS_CONFIG` if set, otherwise the first of
+    /// [CONFIG_FILES] that exists, falling back to `config.json`.
+    pub fn path() -> PathBuf {
+        if let Ok(path) = std::env::var("RUSTARIS_CONFIG") {
+            return PathBuf::from(path);
+        }
+        CONFIG_FILES.iter()
+            .map(PathBuf::from)
+            .find(|path| path.exists())
+            .unwrap_or_else(|| PathBuf::from_str("config.json").unwrap())
+    }
+
+    /// Loads the config file. Without one, the defaults and environment
+    /// overrides are used if they are valid; otherwise a default file is
+    /// created to be filled in. A file that can't be parsed is backed up and the defaults are used,
+    /// so [Config::validate] points out what to fill in.
     pub fn init() -> Self {
-        let config_path = PathBuf::from_str("config.json").unwrap();
+        let config_path = Self::path();
         if config_path.exists() {
-            let mut buf = String::new();
-            fs::File::open(&config_path).expect("Cannot open config file.")
-                .read_to_string(&mut buf).expect("Cannot read config file");
-            serde_json::from_str(&buf).expect("Cannot parse config file")
+            match Self::load() {
+                Ok(config) => config,
+                Err(err) if err.is::<ParseError>() => {
+                    // The logger reads the config, so it isn't up yet.
+                    let backup = crate::back_up(&config_path).expect("Cannot back up config file");
+                    eprintln!(
+                        "Cannot parse config file ({}), moved it to {} and using defaults.", err, backup.display()
+                    );
+                    Self::default().with_env_overrides(std::env::vars()).expect("Cannot load config overrides")
+                }
+                Err(err) => panic!("Cannot load config file: {}", err)
+            }
         }
         else {
-            let mut config_file = fs::File::create_new(&config_path).unwrap();
-            write!(config_file, "{}", serde_json::to_string_pretty(&Self::default())
+            // Without a file, the environment may still be enough to run on.
+            let config = Self::default().with_env_overrides(std::env::vars()).expect("Cannot load config overrides");
+            if config.validate().is_empty() {
+                return config;
+            }
+            crate::write_atomic(&config_path, &ConfigFormat::of(&config_path).render(&Self::default())
                 .expect("Failed to generate default config"))
                 .expect("Failed to write default config file");
-            panic!("Created default config file, please edit it and reboot.")
+            panic!("Created default config file {}, please edit it and reboot.", config_path.display())
         }
     }
-}
\ No newline at end of file
+
+    /// Reads the config file, then applies overrides from the environment.
+    /// See [Config::with_env_overrides].
+    pub fn load() -> anyhow::Result<Self> {
+        let path = Self::path();
+        let mut buf = String::new();
+        fs::File::open(&path)?.read_to_string(&mut buf)?;
+        ConfigFormat::of(&path).parse(&buf).map_err(ParseError)?.with_env_overrides(std::env::vars())
+    }
+
+    /// Overrides fields by variables like `RUSTARIS_HEART_BEAT` or
+    /// `RUSTARIS_NETWORK__WEBSOCKET`, where `__` separates nested keys.
+    /// Keys of maps are given the same way and lowercased, e.g. `RUSTARIS_GROUPS__123__THRESHOLD`.
+    /// So values come from the environment first, then the file, then defaults.
+    /// Variables not naming a config section are ignored, and ones that don't fit
+    /// the config are skipped with a warning. [LEGACY_VARS] are applied first,
+    /// so that the ones they stand for win.
+    pub fn with_env_overrides(self, vars: impl IntoIterator<Item = (String, String)>) -> anyhow::Result<Self> {
+        let mut value = serde_json::to_value(&self)?;
+
+        let (legacy, vars): (Vec<_>, Vec<_>) = vars.into_iter().partition(|(name, _)| {
+            LEGACY_VARS.iter().any(|(legacy, _)| legacy == name)
+        });
+        let legacy = legacy.into_iter().filter_map(|(name, raw)| {
+            LEGACY_VARS.iter().find(|(legacy, _)| *legacy == name).map(|(_, name)| (name.to_string(), raw))
+        });
+
+        for (name, raw) in legacy.chain(vars) {
+            let Some(path) = name.strip_prefix(ENV_PREFIX) else { continue };
+            let keys = path.split("__").map(str::to_lowercase).collect::<Vec<String>>();
+            if value.get(&keys[0]).is_none() {
+                continue;
+            }
+
+            let before = value.clone();
+            if let Err(err) = Self::apply_override(&mut value, &keys, raw) {
+                warn(&format!("Ignored config override {}: {}", name, err));
+                value = before;
+            }
+        }
+
+        serde_json::from_value(value).map_err(|err| anyhow::anyhow!("Invalid config override: {}", err))
+    }
+
+    /// Sets the field at `keys` in `value` to `raw`, adding map entries on the way.
+    /// Fails if the config no longer loads or has no such field, leaving `value` changed.
+    fn apply_override(value: &mut Value, keys: &[String], raw: String) -> anyhow::Result<()> {
+        let Some((last, parents)) = keys.split_last() else { return Ok(()) };
+        let mut target = &mut *value;
+        for (i, key) in parents.iter().enumerate() {
+            let section = target.as_object_mut()
+                .ok_or_else(|| anyhow::anyhow!("{} is not a section", parents[..i].join(".")))?;
+            // Only maps, e.g. `groups`, miss keys. Other sections have all their fields.
+            target = section.entry(key.clone()).or_insert_with(|| json!({}));
+        }
+        let section = target.as_object_mut()
+            .ok_or_else(|| anyhow::anyhow!("{} is not a section", parents.join(".")))?;
+
+        // Strings are taken as they are, anything else is parsed as JSON.
+        // Unset options may hold strings looking like JSON, e.g. a numeric
+        // api key, so they are only parsed if the config still loads.
+        let candidates = match (section.get(last), serde_json::from_str::<Value>(&raw)) {
+            (Some(Value::String(_)), _) | (_, Err(_)) => vec![Value::String(raw)],
+            (Some(Value::Null), Ok(parsed)) => vec![parsed, Value::String(raw)],
+            (_, Ok(parsed)) => vec![parsed]
+        };
+        section.insert(last.clone(), Value::Null);
+
+        let pointer = format!("/{}", keys.join("/"));
+        let mut result = Ok(());
+        for candidate in candidates {
+            if let Some(field) = value.pointer_mut(&pointer) {
+                *field = candidate;
+            }
+            result = serde_json::from_value::<Config>(value.clone())
+                .map_err(anyhow::Error::from)
+                .and_then(|config| match serde_json::to_value(&config)?.pointer(&pointer) {
+                    Some(_) => Ok(()),
+                    None => Err(anyhow::anyhow!("no such config field"))
+                });
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
+
+    /// Re-reads the config file and swaps it into [crate::CONFIG].
+    /// Returns the changed fields that only take effect after a restart.
+    pub fn reload() -> anyhow::Result<Vec<&'static str>> {
+        let new = Self::load()?;
+        let problems = new.validate();
+        if !problems.is_empty() {
+            return Err(anyhow::anyhow!("Invalid config: {}", problems.join("; ")));
+        }
+        let restart = crate::get_config().restart_required(&new)?;
+        crate::CONFIG.store(Arc::new(new));
+        Ok(restart)
+    }
+
+    /// Finds mistakes that would keep the bot from working, e.g. the placeholder
+    /// token left in place. Returns one message per problem.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if !(self.heart_beat > 0.0) {
+            problems.push(format!("heart_beat must be greater than 0, got {}", self.heart_beat));
+        }
+
+        let placeholder = NetworkConfig::default().login_token;
+        // Satori servers may run without a token.
+        let missing = self.network.login_token.is_empty() && self.network.protocol == Protocol::NapCat;
+        if missing || self.network.login_token == placeholder {
+            problems.push(match self.network.protocol {
+                Protocol::NapCat => "network.login_token is not set, use the token of your NapCat servers",
+                Protocol::Satori => "network.login_token is still the placeholder, use the token of your Satori server or leave it empty"
+            }.to_string());
+        }
+        for (field, url, schemes) in [
+            ("network.websocket", &self.network.websocket, ["ws", "wss"]),
+            ("network.http", &self.network.http, ["http", "https"])
+        ] {
+            match reqwest::Url::parse(url) {
+                Ok(parsed) if schemes.contains(&parsed.scheme()) => {},
+                Ok(parsed) => problems.push(format!(
+                    "{} should start with {}://, got {}://", field, schemes[0], parsed.scheme()
+                )),
+                Err(err) => problems.push(format!("{} is not a valid url ({}): {:?}", field, err, url))
+            }
+        }
+
+        for (group_id, group) in &self.groups {
+            if let Some(name) = &group.profile {
+                if !self.thinker.profiles.contains_key(name) {
+                    problems.push(format!("groups.{}.profile is {:?}, which isn't in thinker.profiles", group_id, name));
+                }
+            }
+        }
+        for (name, profile) in &self.thinker.profiles {
+            if let Some(model) = &profile.chat_model {
+                if let Err(err) = crate::thinking::parse_model(model) {
+                    problems.push(format!("thinker.profiles.{}.chat_model: {}", name, err));
+                }
+            }
+        }
+
+        problems
+    }
+
+    /// The api keys and roots that enabled features need but are missing.
+    /// Kept apart from [Config::validate], as they mostly come from the environment.
+    pub fn missing_apis(&self) -> Vec<String> {
+        let api = &self.api;
+        let mut checks = vec![api.deepseek_key(), api.embed_root(), api.embed_key()];
+        if self.tools.vision {
+            checks.extend([api.vision_root(), api.vision_key()]);
+        }
+        if self.tools.web_search {
+            checks.push(api.search_root());
+        }
+        checks.into_iter().filter_map(|check| check.err()).map(|err| err.to_string()).collect()
+    }
+
+    /// Whether dev mode may destroy data: `dev_allow_destructive` is on,
+    /// or `RUSTARIS_CONFIRM_DROP=1` is set.
+    pub fn destructive_allowed(&self) -> bool {
+        self.destructive_allowed_with(std::env::var("RUSTARIS_CONFIRM_DROP").ok().as_deref())
+    }
+
+    /// [Config::destructive_allowed], given the value of `RUSTARIS_CONFIRM_DROP`.
+    fn destructive_allowed_with(&self, confirm: Option<&str>) -> bool {
+        self.dev_allow_destructive || confirm == Some("1")
+    }
+
+    pub fn group(&self, group_id: usize) -> Option<&GroupOverride> {
+        self.groups.get(&group_id.to_string())
+    }
+
+    fn restart_required(&self, new: &Config) -> anyhow::Result<Vec<&'static str>> {
+        let old = serde_json::to_value(self)?;
+        let new = serde_json::to_value(new)?;
+        Ok(RESTART_REQUIRED.iter()
+            .filter(|pointer| old.pointer(pointer) != new.pointer(pointer))
+            .copied()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_env_overrides() -> anyhow::Result<()> {
+        let config = Config::default().with_env_overrides(vars(&[
+            ("RUSTARIS_HEART_BEAT", "1.5"),
+            ("RUSTARIS_NETWORK__WEBSOCKET", "ws://10.0.0.1:3001"),
+            ("RUSTARIS_NETWORK__LOGIN_TOKEN", "12345"),
+            ("RUSTARIS_THINKER__STREAM", "true"),
+            ("RUSTARIS_PERMISSION__ADMINS", "[\"10001\"]"),
+            ("RUSTARIS_CONFIG", "ignored.json"),
+            ("PATH", "/usr/bin")
+        ]))?;
+
+        assert_eq!(config.heart_beat, 1.5);
+        assert_eq!(config.network.websocket, "ws://10.0.0.1:3001");
+        assert_eq!(config.network.login_token, "12345", "字符串字段不应被解析为数字");
+        assert!(config.thinker.stream);
+        assert_eq!(config.permission.admins, vec!["10001"]);
+
+        let skipped = Config::default().with_env_overrides(vars(&[
+            ("RUSTARIS_HEART_BEAT", "fast"),
+            ("RUSTARIS_THINKER__NO_SUCH_FIELD", "1"),
+            ("RUSTARIS_THINKER__STREAM", "true")
+        ]))?;
+        assert_eq!(skipped.heart_beat, Config::default().heart_beat, "类型不符的覆盖应被跳过");
+        assert!(skipped.thinker.stream, "其他覆盖不应受影响");
+
+        let groups = Config::default().with_env_overrides(vars(&[
+            ("RUSTARIS_GROUPS__123__THRESHOLD", "10"),
+            ("RUSTARIS_PERMISSION__OTHER__10001", "5")
+        ]))?;
+        assert_eq!(groups.group(123).and_then(|group| group.threshold), Some(10), "应支持按群号覆盖");
+        assert_eq!(groups.permission.other.get("10001"), Some(&5));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_destructive_allowed() {
+        let mut config = Config::default();
+        assert!(!config.dev, "开发模式应默认关闭");
+        assert!(!config.destructive_allowed_with(None), "未确认时不应允许删除数据");
+        assert!(!config.destructive_allowed_with(Some("0")));
+        assert!(config.destructive_allowed_with(Some("1")), "RUSTARIS_CONFIRM_DROP=1 时应允许");
+        config.dev_allow_destructive = true;
+        assert!(config.destructive_allowed_with(None));
+    }
+
+    #[test]
+    fn test_api_overrides() -> anyhow::Result<()> {
+        let config = Config::default().with_env_overrides(vars(&[
+            ("RUSTARIS_API__EMBED_KEY", "123456"),
+            ("RUSTARIS_API__DEEPSEEK_KEY", "new"),
+            ("API_KEY", "old"),
+            ("EMBED_API_ROOT", "http://embed")
+        ]))?;
+
+        assert_eq!(config.api.embed_key.as_deref(), Some("123456"), "数字形式的密钥应作为字符串");
+        assert_eq!(config.api.deepseek_key.as_deref(), Some("new"), "带前缀的变量应优先");
+        assert_eq!(config.api.embed_root.as_deref(), Some("http://embed"), "旧的变量名应仍然有效");
+
+        let config = Config::default().with_env_overrides(vars(&[("RUSTARIS_MEMORY__TS_EXTENSION", "zhparser")]))?;
+        assert_eq!(config.memory.ts_extension.as_deref(), Some("zhparser"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_required_api() {
+        let unset = "RUSTARIS_TEST_UNSET_KEY";
+        assert_eq!(required("Key", unset, "api.key", &Some("abc".to_string())).unwrap(), "abc", "应使用配置中的值");
+
+        let err = required("Key", unset, "api.key", &None).unwrap_err().to_string();
+        assert!(err.contains(unset) && err.contains("api.key"), "错误信息应指明如何设置：{}", err);
+        assert!(required("Key", unset, "api.key", &Some(String::new())).is_err(), "空值应视为未设置");
+    }
+
+    #[test]
+    fn test_validate() {
+        let problems = Config::default().validate();
+        assert!(problems.iter().any(|p| p.contains("login_token")), "应检查出占位的 token");
+
+        let mut satori = Config::default();
+        satori.network.protocol = Protocol::Satori;
+        assert!(satori.validate().iter().any(|p| p.contains("login_token") && p.contains("Satori")), "应按协议提示 token");
+
+        let mut config = Config::default();
+        config.network.login_token = "token".to_string();
+        assert!(config.validate().is_empty(), "默认地址应通过检查：{:?}", config.validate());
+
+        config.heart_beat = 0.0;
+        config.network.websocket = "127.0.0.1:3001".to_string();
+        config.network.http = "ws://127.0.0.1:3000".to_string();
+        assert_eq!(config.validate().len(), 3, "{:?}", config.validate());
+    }
+
+    #[test]
+    fn test_validate_profiles() {
+        let mut config = Config::default();
+        config.network.login_token = "token".to_string();
+        config.groups.insert("114514".to_string(), GroupOverride { profile: Some("work".to_string()), ..Default::default() });
+        assert!(config.validate().iter().any(|p| p.contains("114514")), "应检查出不存在的人设");
+
+        config.thinker.profiles.insert("work".to_string(), PromptProfile::default());
+        assert!(config.validate().is_empty(), "{:?}", config.validate());
+
+        config.thinker.profiles.insert("work".to_string(), PromptProfile { chat_model: Some("gpt".to_string()), ..Default::default() });
+        assert_eq!(config.validate().len(), 1, "应检查出未知的模型");
+    }
+
+    #[test]
+    fn test_config_formats() -> anyhow::Result<()> {
+        let mut config = Config::default();
+        config.groups.insert("114514".to_string(), GroupOverride { threshold: Some(30), ..Default::default() });
+
+        for format in [ConfigFormat::Json, ConfigFormat::Toml, ConfigFormat::Yaml] {
+            let parsed = format.parse(&format.render(&config)?)?;
+            assert_eq!(parsed.network.websocket, config.network.websocket);
+            assert_eq!(parsed.group(114514).and_then(|group| group.threshold), Some(30), "群覆盖配置应能读写");
+        }
+
+        assert!(matches!(ConfigFormat::of(Path::new("config.yml")), ConfigFormat::Yaml));
+        Ok(())
+    }
+}
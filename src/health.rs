@@ -0,0 +1,82 @@
+//! An optional HTTP server for orchestrators to probe, see [HealthConfig].
+//! `/healthz` answers 200 while the bot is online and the database answers,
+//! `/readyz` once startup has finished. `/metrics` serves [crate::metrics].
+//!
+//! [HealthConfig]: crate::config::HealthConfig
+
+use std::{sync::{Arc, OnceLock, atomic::Ordering}, time::Duration};
+
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}, spawn, task::JoinHandle, time::timeout};
+
+use crate::{CONNECTED, get_logger, memory::MemoryService, metrics::METRICS};
+
+const OK: &str = "200 OK";
+const UNAVAILABLE: &str = "503 Service Unavailable";
+/// How long a client may take to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the database may take to answer `/healthz`, after which it counts as down.
+const PING_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Set once startup has finished. `/healthz` checks its database.
+static READY: OnceLock<Arc<MemoryService>> = OnceLock::new();
+
+/// Marks startup as finished.
+pub fn set_ready(mem_service: Arc<MemoryService>) {
+    let _ = READY.set(mem_service);
+}
+
+/// Starts answering probes on `bind:port`. The server runs until the task is aborted.
+pub async fn serve(bind: &str, port: u16) -> std::io::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind((bind, port)).await?;
+    Ok(spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => { spawn(handle(stream)); },
+                Err(err) => get_logger().debug(&format!("Error accepting probe: {}", err))
+            }
+        }
+    }))
+}
+
+async fn handle(mut stream: TcpStream) {
+    let mut buf = [0u8; 1024];
+    let Ok(Ok(len)) = timeout(READ_TIMEOUT, stream.read(&mut buf)).await else { return };
+    // Only the path in the request line matters, as in `GET /healthz HTTP/1.1`.
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let path = request.split_whitespace().nth(1).unwrap_or("/");
+
+    let (status, body) = respond(path.split('?').next().unwrap_or(path)).await;
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+}
+
+/// The status line and body answering a request for `path`.
+async fn respond(path: &str) -> (&'static str, String) {
+    let healthy = match path {
+        "/healthz" => match READY.get() {
+            Some(mem_service) => CONNECTED.load(Ordering::Relaxed)
+                && timeout(PING_TIMEOUT, mem_service.ping()).await.unwrap_or(false),
+            None => false
+        },
+        "/readyz" => READY.get().is_some(),
+        "/metrics" => return (OK, METRICS.render()),
+        _ => return ("404 Not Found", "not found".to_string())
+    };
+    if healthy { (OK, "ok".to_string()) } else { (UNAVAILABLE, "unavailable".to_string()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_respond_before_ready() {
+        assert_eq!(respond("/readyz").await.0, UNAVAILABLE, "启动完成前不应就绪");
+        assert_eq!(respond("/healthz").await.0, UNAVAILABLE, "启动完成前不应健康");
+        assert_eq!(respond("/unknown").await.0, "404 Not Found");
+        assert_eq!(respond("/metrics").await.0, OK, "指标应随时可读");
+    }
+}
@@ -1,5 +1,7 @@
-use std::sync::{Arc, LazyLock, Mutex};
+use std::{fs, io::{self, Write}, path::{Path, PathBuf}, sync::{Arc, LazyLock, Mutex, atomic::AtomicBool}, time::Instant};
 
+use arc_swap::ArcSwap;
+use tokio::sync::watch;
 use lazy_static::lazy_static;
 use crate::{adapters::APIWrapper, config::Config, logging::Logger};
 
@@ -11,14 +13,27 @@ pub mod commands;
 pub mod thinking;
 pub mod memory;
 pub mod tools;
+pub mod reminders;
+pub mod vision;
+pub mod health;
+pub mod metrics;
+pub mod preflight;
 
 
-pub const DEV: bool = true;
+/// Swapped by [Config::reload]. Read it through [get_config].
+pub static CONFIG: LazyLock<ArcSwap<Config>> = LazyLock::new(|| {
+    ArcSwap::from_pointee(Config::init())
+});
 
+pub fn get_config() -> Arc<Config> {
+    CONFIG.load_full()
+}
 
-pub static CONFIG: LazyLock<Config> = LazyLock::new(|| {
-    Config::init()
-});
+/// When the process started, forced at the start of `main`.
+pub static START_TIME: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+/// Whether the adapter's websocket is connected and the bot is online.
+pub static CONNECTED: AtomicBool = AtomicBool::new(false);
 
 lazy_static! {
     pub static ref LOGGER: Arc<Mutex<Option<Logger>>> =
@@ -48,9 +63,91 @@ pub fn get_poster() -> APIWrapper {
 }
 
 
-pub fn set_exit_handler(status: &Arc<Mutex<bool>>) {
+/// Whether the tasks sharing it should keep running. Clones share the flag,
+/// and everyone waiting on [Status::stopped] wakes as soon as it is stopped.
+#[derive(Clone)]
+pub struct Status(Arc<watch::Sender<bool>>);
+
+impl Status {
+    pub fn new() -> Self {
+        Self(Arc::new(watch::Sender::new(true)))
+    }
+
+    pub fn running(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    pub fn stop(&self) {
+        self.0.send_replace(false);
+    }
+
+    /// Completes once [Status::stop] has been called, right away if it already was.
+    pub async fn stopped(&self) {
+        let _ = self.0.subscribe().wait_for(|running| !running).await;
+    }
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn set_exit_handler(status: &Status) {
     let exit = status.clone();
     ctrlc::set_handler(move || {
-        *exit.lock().unwrap() = false;
+        exit.stop();
     }).expect("Fail to set ctrlc handler");
-}
\ No newline at end of file
+}
+
+/// Replaces the file at `path` with `contents`. They are written to a temporary
+/// file that is renamed into place, so a crash never leaves a half-written file.
+pub fn write_atomic(path: &Path, contents: &str) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+/// Moves a file that couldn't be parsed out of the way, to
+/// `<path>.<timestamp>.bak`, so earlier backups are kept.
+/// Returns where it was moved.
+pub fn back_up(path: &Path) -> io::Result<PathBuf> {
+    let stamp = chrono::Local::now().format("%Y%m%d-%H%M%S");
+    let mut backup = PathBuf::from(format!("{}.{}.bak", path.display(), stamp));
+    let mut n = 1;
+    while backup.exists() {
+        backup = PathBuf::from(format!("{}.{}-{}.bak", path.display(), stamp, n));
+        n += 1;
+    }
+    fs::rename(path, &backup)?;
+    Ok(backup)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_atomic() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("rustaris_atomic_{}.json", std::process::id()));
+
+        write_atomic(&path, "{\"a\": 1}")?;
+        write_atomic(&path, "{}")?;
+        assert_eq!(fs::read_to_string(&path)?, "{}", "应完整替换旧内容");
+        assert!(!Path::new(&format!("{}.tmp", path.display())).exists(), "临时文件应被移走");
+
+        let backup = back_up(&path)?;
+        assert!(!path.exists() && backup.exists(), "原文件应被移至备份");
+        write_atomic(&path, "{}")?;
+        let second = back_up(&path)?;
+        assert!(backup.exists() && second.exists() && second != backup, "不应覆盖之前的备份");
+        fs::remove_file(backup)?;
+        fs::remove_file(second)?;
+        Ok(())
+    }
+}
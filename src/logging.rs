@@ -1,29 +1,37 @@
-use chrono::Local;
+use std::{fs::{self, File, OpenOptions}, io::{self, IsTerminal, Write}, path::{Path, PathBuf}, sync::Arc};
+
+use chrono::{DateTime, Local, NaiveDate};
 use colored::{Color, Colorize};
 use tokio::{sync::mpsc::{self, UnboundedReceiver, UnboundedSender}, task::JoinHandle};
 use dyn_fmt::AsStrFormatExt;
+use serde_json::json;
 
-use crate::{CONFIG, LOGGER};
+use crate::{LOGGER, config::{Config, LogFormat, LoggerConfig}, get_config};
 
 const META_TEMP: &'static str = "[{}] {} {} {} ";
+/// Used when file logging is on but `save_path` isn't set.
+const DEFAULT_LOG_PATH: &str = "./logs/rustaris.log";
 
 pub enum LogMsg {
     INFO(String),
     WARN(String),
     ERROR(String),
     CHAT(String),
-    DEBUG(String)
+    DEBUG(String),
+    /// Verbose payload dumps, e.g. websocket frames and api responses.
+    TRACE(String)
 }
 
 impl LogMsg {
 
-    pub fn enabled(&self) -> bool {
+    pub fn enabled(&self, config: &LoggerConfig) -> bool {
         match self {
-            Self::INFO(_) => CONFIG.logger.info,
-            Self::WARN(_) => CONFIG.logger.warning,
-            Self::ERROR(_) => CONFIG.logger.error,
-            Self::CHAT(_) => CONFIG.logger.chat,
-            Self::DEBUG(_) => CONFIG.logger.debug
+            Self::INFO(_) => config.info,
+            Self::WARN(_) => config.warning,
+            Self::ERROR(_) => config.error,
+            Self::CHAT(_) => config.chat,
+            Self::DEBUG(_) => config.debug,
+            Self::TRACE(_) => config.trace
         }
     }
 
@@ -33,18 +41,30 @@ impl LogMsg {
             Self::WARN (content) => ("⚠️", "Warn ", Color::Yellow, content),
             Self::ERROR(content) => ("❌", "Error", Color::Red, content),
             Self::CHAT (content) => ("💬", "Chat ", Color::Green, content),
-            Self::DEBUG(content) => ("⚙️", "Debug", Color::Magenta, content)
+            Self::DEBUG(content) => ("⚙️", "Debug", Color::Magenta, content),
+            Self::TRACE(content) => ("🔍", "Trace", Color::BrightBlack, content)
         }
     }
 }
 
 pub struct LoggerProvider {
     receiver: UnboundedReceiver<LogMsg>,
+    /// Where logs are also written if `generate_file` is on.
+    file: Option<LogFile>,
+    /// Where the enabled levels and the format are read for each message,
+    /// [get_config] unless given to [LoggerProvider::init_with].
+    config: fn() -> Arc<Config>
 }
 impl LoggerProvider {
     pub fn init() -> JoinHandle<()> {
+        Self::init_with(get_config)
+    }
+
+    /// Like [LoggerProvider::init], with the logger settings read from `config`.
+    pub fn init_with(config: fn() -> Arc<Config>) -> JoinHandle<()> {
         let (sender, receiver) = mpsc::unbounded_channel::<LogMsg>();
-        let mut provider = Self { receiver };
+        colored::control::set_override(Self::use_color(&config().logger));
+        let mut provider = Self { receiver, file: Self::open_file(&config().logger), config };
         let logger = Logger { sender };
         LOGGER.lock().unwrap().replace(logger);
         tokio::spawn(async move {
@@ -52,30 +72,75 @@ impl LoggerProvider {
         })
     }
 
+    /// Outputs messages until every [Logger] is dropped. Messages still queued
+    /// by then are output before returning, so nothing is lost on shutdown.
     pub async fn run(&mut self) {
-        loop {
-            if let Some(msg) = self.receiver.recv().await {
+        // `recv` only gives `None` once all senders are dropped
+        // and the queue is empty.
+        while let Some(msg) = self.receiver.recv().await {
+            self.output(msg);
+        }
+    }
+
+    fn output(&mut self, msg: LogMsg) {
+        let config = (self.config)();
+        if !msg.enabled(&config.logger) {
+            return;
+        }
+
+        let (level_icon, level_str, level_color, content) = msg.split();
 
-                if !msg.enabled() {
-                    continue;
-                }
+        if config.logger.format == LogFormat::Json {
+            let line = json!({
+                "timestamp": Local::now().to_rfc3339(),
+                "level": level_str.trim().to_lowercase(),
+                "message": content
+            }).to_string();
+            self.write_file(&line);
+            println!("{}", line);
+            return;
+        }
+
+        let time = Local::now().format("%H:%M:%S").to_string();
+        let meta_len = META_TEMP.format(&[&time, level_icon, level_str, "|"]).len();
 
-                let (level_icon, level_str, level_color, content) = msg.split();
+        let content = content.replace("\n", &("\n".to_string() + &" ".repeat(meta_len)));
 
-                let time = Local::now().format("%H:%M:%S").to_string();
-                let meta_len = META_TEMP.format(&[&time, level_icon, level_str, "|"]).len();
+        // Written before coloring, so the file gets no ANSI codes.
+        self.write_file(&(META_TEMP.format(&[&time, level_icon, level_str, "|"]) + &content));
 
-                let content = content.replace("\n", &("\n".to_string() + &" ".repeat(meta_len)));
+        let time = time.color(Color::BrightBlack).to_string();
+        let level_str = level_str.bold().color(level_color).to_string();
 
-                let time = time.color(Color::BrightBlack).to_string();
-                let level_str = level_str.bold().color(level_color).to_string();
+        println!("{}", META_TEMP.format(&[&time, level_icon, &level_str, "|"]) + &content);
+    }
 
-                println!("{}", META_TEMP.format(&[&time, level_icon, &level_str, "|"]) + &content);
+    fn use_color(config: &LoggerConfig) -> bool {
+        config.color.unwrap_or_else(|| {
+            io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+        })
+    }
 
-            } else {
-                // If None is returned, that means the original `Logger`
-                // in the lazy_lock and all other `Logger`s has been dropped.
-                break;
+    fn write_file(&mut self, line: &str) {
+        if let Some(file) = &mut self.file {
+            if let Err(err) = file.write_line(line) {
+                eprintln!("Failed to write log file, file logging disabled: {}", err);
+                self.file = None;
+            }
+        }
+    }
+
+    fn open_file(config: &LoggerConfig) -> Option<LogFile> {
+        if !config.generate_file {
+            return None;
+        }
+
+        let path = PathBuf::from(config.save_path.as_deref().unwrap_or(DEFAULT_LOG_PATH));
+        match LogFile::open(path.clone(), config.max_size_mb * 1024 * 1024, config.max_files) {
+            Ok(file) => Some(file),
+            Err(err) => {
+                eprintln!("Cannot open log file {}: {}", path.display(), err);
+                None
             }
         }
     }
@@ -85,6 +150,106 @@ impl LoggerProvider {
     }
 }
 
+/// A log file rotated by size and by day.
+/// Archives are named like `rustaris.2025-01-01.log`, or `rustaris.2025-01-01.1.log`
+/// if rotated more than once that day.
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    /// The day the current file was started.
+    date: NaiveDate,
+    /// `0` means no size limit.
+    max_size: u64,
+    max_files: usize
+}
+
+impl LogFile {
+    fn open(path: PathBuf, max_size: u64, max_files: usize) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        let date = metadata.modified()
+            .map(|time| DateTime::<Local>::from(time).date_naive())
+            .unwrap_or_else(|_| Local::now().date_naive());
+
+        Ok(Self { path, file, size: metadata.len(), date, max_size, max_files })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let today = Local::now().date_naive();
+        if self.size > 0 && (today != self.date || (self.max_size > 0 && self.size >= self.max_size)) {
+            self.rotate(today)?;
+        }
+
+        writeln!(self.file, "{}", line)?;
+        self.file.flush()?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn rotate(&mut self, today: NaiveDate) -> io::Result<()> {
+        let (stem, ext) = self.name_parts();
+        let dir = self.dir();
+
+        let mut archive = dir.join(format!("{}.{}.{}", stem, self.date, ext));
+        let mut n = 1;
+        while archive.exists() {
+            archive = dir.join(format!("{}.{}.{}.{}", stem, self.date, n, ext));
+            n += 1;
+        }
+
+        fs::rename(&self.path, &archive)?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        self.date = today;
+
+        self.prune()
+    }
+
+    /// Deletes the oldest archives beyond `max_files`.
+    fn prune(&self) -> io::Result<()> {
+        let mut archives = fs::read_dir(self.dir())?
+            .filter_map(Result::ok)
+            .filter(|entry| self.is_archive(&entry.file_name().to_string_lossy()))
+            .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+            .collect::<Vec<_>>();
+        archives.sort();
+
+        let excess = archives.len().saturating_sub(self.max_files);
+        for (_, path) in archives.into_iter().take(excess) {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Whether `name` is one [LogFile::rotate] gives, like `rustaris.2024-01-31.log`
+    /// or `rustaris.2024-01-31.2.log`.
+    fn is_archive(&self, name: &str) -> bool {
+        let (stem, ext) = self.name_parts();
+        let Some(middle) = name.strip_prefix(&format!("{}.", stem))
+            .and_then(|rest| rest.strip_suffix(&format!(".{}", ext))) else {
+            return false;
+        };
+        let mut parts = middle.split('.');
+        parts.next().is_some_and(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").is_ok())
+            && parts.next().is_none_or(|n| n.parse::<usize>().is_ok())
+            && parts.next().is_none()
+    }
+
+    fn dir(&self) -> &Path {
+        self.path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."))
+    }
+
+    fn name_parts(&self) -> (String, String) {
+        let stem = self.path.file_stem().map_or("rustaris".into(), |s| s.to_string_lossy().to_string());
+        let ext = self.path.extension().map_or("log".into(), |s| s.to_string_lossy().to_string());
+        (stem, ext)
+    }
+}
+
 #[derive(Clone)]
 pub struct Logger {
     sender: UnboundedSender<LogMsg>
@@ -109,4 +274,55 @@ impl Logger {
     pub fn debug(&self, msg: &str) {
         let _ = self.sender.send(LogMsg::DEBUG(msg.to_string()));
     }
-}
\ No newline at end of file
+
+    pub fn trace(&self, msg: &str) {
+        let _ = self.sender.send(LogMsg::TRACE(msg.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drains_queue_on_exit() -> io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("rustaris_drain_{}", std::process::id()));
+        let path = dir.join("rustaris.log");
+        let (sender, receiver) = mpsc::unbounded_channel::<LogMsg>();
+        let mut provider = LoggerProvider {
+            receiver,
+            file: Some(LogFile::open(path.clone(), 0, 1)?),
+            config: || Arc::new(Config::default())
+        };
+        let logger = Logger { sender };
+
+        for i in 0..100 {
+            logger.error(&format!("queued {}", i));
+        }
+        drop(logger);
+        provider.run().await;
+
+        let written = fs::read_to_string(&path)?;
+        assert_eq!(written.lines().count(), 100, "退出前应写完所有排队的日志");
+        assert!(written.lines().next_back().is_some_and(|line| line.contains("queued 99")), "日志应按顺序写入");
+        fs::remove_dir_all(dir)
+    }
+
+    #[test]
+    fn test_prune_only_archives() -> io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("rustaris_prune_{}", std::process::id()));
+        let file = LogFile::open(dir.join("rustaris.log"), 0, 1)?;
+        for name in ["rustaris.2024-01-01.log", "rustaris.2024-01-01.1.log", "rustaris.backup.log", "rustaris.2024-01-01.old.log"] {
+            fs::write(dir.join(name), "")?;
+        }
+
+        file.prune()?;
+        let archives = ["rustaris.2024-01-01.log", "rustaris.2024-01-01.1.log"].iter()
+            .filter(|name| dir.join(name).exists())
+            .count();
+        assert_eq!(archives, 1, "应只保留 max_files 个归档");
+        assert!(dir.join("rustaris.backup.log").exists() && dir.join("rustaris.2024-01-01.old.log").exists(), "不应删除其他文件");
+        assert!(dir.join("rustaris.log").exists(), "不应删除当前日志");
+        fs::remove_dir_all(dir)
+    }
+}
@@ -1,54 +1,126 @@
-use std::{sync::{Arc, Mutex}, time::Duration};
+use std::{sync::LazyLock, time::Duration};
 
 use rustaris_ds::{
-    CONFIG, DEV, adapters, commands::run_cmds, get_logger, logging::LoggerProvider, objects::Event, set_exit_handler, thinking::{self, Thinker}
+    START_TIME, adapters, commands::{AliasCommand, CommandRegistry, DozeCommand, EchoCommand, HelpCommand, KickCommand, MemoryCommand, MuteCommand, ReloadCommand, StatusCommand, ToolsCommand}, config::Config, get_config, get_logger, get_poster, health, logging::LoggerProvider, metrics::{self, METRICS}, objects::Event, preflight, Status, set_exit_handler, thinking::{self, Thinker}
 };
 
-use tokio::time::sleep;
+use tokio::{select, sync::mpsc::unbounded_channel, time::{sleep, timeout}};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
 
+    LazyLock::force(&START_TIME);
+    // Loaded before anything reads the config, which may be overridden by `.env`.
+    dotenv::dotenv().ok();
+
+    let mut problems = get_config().validate();
+    problems.extend(get_config().missing_apis());
+    if !problems.is_empty() {
+        eprintln!("Please fix {} before starting:", Config::path().display());
+        for problem in problems {
+            eprintln!("  - {}", problem);
+        }
+        std::process::exit(1);
+    }
+
     let logger_thread = LoggerProvider::init();
     let logger = get_logger();
 
-    if DEV { logger.warn("Running in Dev mode..."); }
-    dotenv::dotenv().ok();
+    if get_config().dev { logger.warn("Running in Dev mode..."); }
+
+    let health_thread = if get_config().health.enabled {
+        match health::serve(&get_config().health.bind, get_config().health.port).await {
+            Ok(thread) => Some(thread),
+            Err(err) => {
+                logger.error(&format!("Cannot start health check server: {}", err));
+                None
+            }
+        }
+    } else { None };
 
-    let status = Arc::new(Mutex::new(true));
+    let status = Status::new();
     set_exit_handler(&status);
+    #[cfg(unix)]
+    reload_on_hangup();
+
+    let (event_sender, mut events) = unbounded_channel::<Event>();
+    let adapter = adapters::init(&get_config(), event_sender);
 
-    let (listener, poster) = adapters::napcat::get_pair();
-    let adapter_status = listener.status.clone();
-    let events = listener.events.clone();
-    let adapter_thread = adapters::napcat::run_pair(listener, poster);
+    let thinker = Thinker::init(get_poster()).await?;
 
-    let thinker = Thinker::init().await?;
-    let thinker_status = thinker.status.clone();
-    let (thinker_thread, think_end) = thinking::run(thinker);
+    if get_config().preflight {
+        let failures = preflight::run(&thinker.dozer.mem_service, &thinker.client, &thinker.chat_model).await;
+        if !failures.is_empty() {
+            return Err(anyhow::anyhow!("Preflight failed:\n  - {}", failures.join("\n  - ")));
+        }
+    }
 
-    while *status.lock().unwrap() {
-        if let Some(event) = events.lock().unwrap().pop_front() {
-            match event {
+    let mut commands = CommandRegistry::new();
+    commands.register(EchoCommand);
+    commands.register(StatusCommand {
+        stats: thinker.stats.clone(),
+        mem_service: thinker.dozer.mem_service.clone()
+    });
+    commands.register(ToolsCommand { metrics: thinker.tools.metrics() });
+    commands.register(ReloadCommand);
+    commands.register(MemoryCommand { excluded: thinker.dozer.excluded.clone() });
+    commands.register(AliasCommand { map: thinker.dozer.alia_map.clone() });
+    commands.register(DozeCommand { flush: thinker.flush_sender.clone() });
+    commands.register(MuteCommand);
+    commands.register(KickCommand);
+    commands.register(HelpCommand::new(&commands, &thinker.tools));
+    logger.info(&format!("Tools: {}", thinker.tools.names().join(", ")));
+
+    let alia_map = thinker.dozer.alia_map.clone();
+    health::set_ready(thinker.dozer.mem_service.clone());
+    let (mut thinker_thread, think_end) = thinking::run(thinker);
+
+    loop {
+        select! {
+            Some(event) = events.recv() => match event {
                 Event::Message(msg) => {
+                    metrics::inc(&METRICS.messages_received);
                     logger.chat(&format!("Msg: {} from {}", msg.raw, msg.sender.user_id));
-                    if !run_cmds(msg.clone()).await {
+                    if !commands.run_cmds(&msg).await {
                         let _ = think_end.send(msg);
                     }
                 }
+            },
+            _ = status.stopped() => break,
+            // Exiting is signalled through `status`, this is only a fallback.
+            _ = sleep(Duration::from_secs_f32(get_config().heart_beat)) => {
+                if !status.running() { break; }
             }
         }
-        sleep(Duration::from_secs_f32(CONFIG.heart_beat)).await;
     }
 
     logger.info("Exiting......");
-    
-    *adapter_status.lock().unwrap() = false;
-    *thinker_status.lock().unwrap() = false;
 
-    adapter_thread.await?;
-    thinker_thread.await?;
+    // Events still arriving are left in `events` unhandled. Dropping the sender
+    // lets the thinker finish what it has and flush the dozer, while the
+    // adapter stays up so its replies still go out.
+    logger.info("Stopped accepting events, waiting for the thinker...");
+    drop(think_end);
+    let grace = Duration::from_secs(get_config().thinker.shutdown_grace_secs);
+    if timeout(grace, &mut thinker_thread).await.is_err() {
+        logger.warn(&format!("Thinker didn't finish within {}s, stopping it.", grace.as_secs()));
+        thinker_thread.abort();
+    }
+
+    logger.info("Saving aliases...");
+    if let Err(err) = alia_map.lock().unwrap().save() {
+        logger.error(&format!("Error saving aliases: {}", err));
+    }
 
+    logger.info("Closing adapter...");
+    adapter.close().await?;
+
+    if let Some(thread) = health_thread {
+        thread.abort();
+    }
+
+    // The logger thread ends once every `Logger` is dropped, after printing
+    // whatever is still queued, so all logs above are kept.
     drop(logger);
     LoggerProvider::exit();
     logger_thread.await?;
@@ -56,13 +128,36 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Reloads the config whenever the process gets SIGHUP.
+#[cfg(unix)]
+fn reload_on_hangup() {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    tokio::spawn(async {
+        let Ok(mut hangup) = signal(SignalKind::hangup()) else { return };
+        while hangup.recv().await.is_some() {
+            let logger = get_logger();
+            match Config::reload() {
+                Ok(restart) => {
+                    logger.info("Config reloaded.");
+                    if !restart.is_empty() {
+                        logger.warn(&format!("Changes to {} take effect after a restart.", restart.join(", ")));
+                    }
+                }
+                Err(err) => logger.error(&format!("Error reloading config: {}", err))
+            }
+        }
+    });
+}
+
 
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::Arc;
     use rust_mc_status::McClient;
-    use rustaris_ds::memory::{MemoryService, Scope};
+    use rustaris_ds::{memory::{MemoryService, Scope}, tools::{AddMemoryTool, DeleteMemoryTool, Tool}};
     use serde_json::Value;
     use tokio;
 
@@ -71,12 +166,12 @@ mod tests {
         let logger_thread = LoggerProvider::init();
         
         // 初始化内存服务
-        let mem_service = MemoryService::init().await?;
+        let mem_service = MemoryService::init(&get_config().memory).await?;
         
         // 测试创建记忆
         let scope = Scope::Group(114514);
         let content = "Falsw最喜欢的人是小一";
-        mem_service.create(scope, content).await?;
+        mem_service.create(scope, content, None).await?;
         
         // 测试相似记忆检索
         let similar_memories = mem_service.similars(scope, content).await?;
@@ -94,6 +189,12 @@ mod tests {
         assert!(!updated_memories.is_empty(), "应该找到更新后的记忆");
         assert_eq!(updated_memories[0].content, updated_content, "更新后的记忆内容应该匹配");
         assert_eq!(updated_memories[0].confidence, 0.8, "置信度应该正确更新");
+
+        // 测试只更新置信度
+        mem_service.merge_confidence(updated_memories[0].id, 0.9).await?;
+        assert_eq!(mem_service.content(updated_memories[0].id).await?.as_deref(), Some(updated_content), "内容不应改变");
+        let updated_memories = mem_service.similars(scope, updated_content).await?;
+        assert_eq!(updated_memories[0].confidence, 0.9, "置信度应该正确更新");
         
         // 测试删除记忆
         mem_service.delete(updated_memories[0].id).await?;
@@ -101,6 +202,21 @@ mod tests {
         // 验证记忆已被删除
         let deleted_memories = mem_service.similars(scope, updated_content).await?;
         assert!(deleted_memories.is_empty(), "删除后应该找不到记忆");
+
+        // 测试批量删除
+        mem_service.create(scope, "小一喜欢吃苹果", None).await?;
+        mem_service.create(scope, "小一喜欢吃香蕉", None).await?;
+        let ids = mem_service.similars(scope, "小一喜欢吃").await?
+            .iter().map(|memory| memory.id).collect::<Vec<i32>>();
+        assert_eq!(mem_service.delete_many(&ids).await?, ids.len() as u64, "应返回实际删除的条数");
+        assert_eq!(mem_service.delete_many(&ids).await?, 0, "重复删除不应计数");
+
+        // 测试去重
+        assert!(mem_service.create_deduped(scope, "小一养了一只猫", None, 0.95).await?, "首次添加应插入");
+        assert!(!mem_service.create_deduped(scope, "小一养了一只猫", None, 0.95).await?, "重复的记忆不应插入");
+        let cats = mem_service.similars(scope, "小一养了一只猫").await?;
+        assert_eq!(cats.iter().filter(|memory| memory.content == "小一养了一只猫").count(), 1, "应只有一条记忆");
+        mem_service.delete_many(&cats.iter().map(|memory| memory.id).collect::<Vec<i32>>()).await?;
         
         LoggerProvider::exit();
         logger_thread.await?;
@@ -108,6 +224,165 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_init_schema_idempotent() -> anyhow::Result<()> {
+        let logger_thread = LoggerProvider::init();
+
+        let mem_service = MemoryService::init(&get_config().memory).await?;
+        mem_service.init_schema(false, false).await?;
+        mem_service.init_schema(false, false).await?;
+
+        LoggerProvider::exit();
+        logger_thread.await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_similars_limit() -> anyhow::Result<()> {
+        let logger_thread = LoggerProvider::init();
+
+        let mut mem_service = MemoryService::init(&get_config().memory).await?;
+
+        let scope = Scope::Group(1919810);
+        for content in ["Falsw喜欢猫", "Falsw喜欢狗", "Falsw喜欢鸟"] {
+            mem_service.create(scope, content, None).await?;
+        }
+
+        mem_service.retrieval.limit = 3;
+        let memories = mem_service.similars(scope, "Falsw喜欢").await?;
+        assert_eq!(memories.len(), 3, "limit 为 3 时应返回 3 条记忆");
+
+        mem_service.retrieval.limit = 1;
+        let memories = mem_service.similars(scope, "Falsw喜欢").await?;
+        assert_eq!(memories.len(), 1, "limit 为 1 时应只返回 1 条记忆");
+
+        mem_service.retrieval.limit = 3;
+        for memory in mem_service.similars(scope, "Falsw喜欢").await? {
+            mem_service.delete(memory.id).await?;
+        }
+
+        LoggerProvider::exit();
+        logger_thread.await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_and_count() -> anyhow::Result<()> {
+        let logger_thread = LoggerProvider::init();
+
+        let mem_service = MemoryService::init(&get_config().memory).await?;
+
+        for scope in [Scope::Group(8101), Scope::Global] {
+            let before = mem_service.count(scope).await?;
+            for i in 0..5 {
+                mem_service.create(scope, &format!("分页测试记忆 {}", i), None).await?;
+            }
+            assert_eq!(mem_service.count(scope).await?, before + 5, "应计入新增的记忆");
+
+            let first = mem_service.list(scope, 2, 0).await?;
+            let second = mem_service.list(scope, 2, 2).await?;
+            assert_eq!(first.len(), 2, "每页应有 2 条");
+            assert_eq!(first[0].content, "分页测试记忆 4", "应按创建时间倒序");
+            assert_eq!(second[0].content, "分页测试记忆 2", "第二页应接着第一页");
+            assert!(first.iter().chain(&second).all(|memory| memory.scope == scope), "不应列出其他范围的记忆");
+
+            let ids = mem_service.list(scope, 5, 0).await?
+                .iter().map(|memory| memory.id).collect::<Vec<i32>>();
+            mem_service.delete_many(&ids).await?;
+        }
+
+        LoggerProvider::exit();
+        logger_thread.await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_embed_cache() -> anyhow::Result<()> {
+        let logger_thread = LoggerProvider::init();
+        dotenv::dotenv().ok();
+
+        let mut server = mockito::Server::new_async().await;
+        let mock = server.mock("POST", "/")
+            .with_header("content-type", "application/json")
+            .with_body(serde_json::json!({
+                "data": [{ "index": 0, "embedding": vec![0.1; get_config().memory.embed_dim] }]
+            }).to_string())
+            .expect(1)
+            .create_async().await;
+
+        let mut mem_service = MemoryService::init(&get_config().memory).await?;
+        mem_service.embed_api_root = server.url();
+
+        let first = mem_service.embed("Falsw").await?;
+        let second = mem_service.embed("Falsw").await?;
+        assert_eq!(first, second, "缓存的向量应与首次请求一致");
+        mock.assert_async().await;
+
+        LoggerProvider::exit();
+        logger_thread.await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_export_import() -> anyhow::Result<()> {
+        let logger_thread = LoggerProvider::init();
+
+        let mem_service = MemoryService::init(&get_config().memory).await?;
+
+        let scope = Scope::Group(364364);
+        mem_service.create(scope, "Falsw住在上海", None).await?;
+        mem_service.create(scope, "Falsw会弹吉他", None).await?;
+        let before = mem_service.similars(scope, "Falsw").await?;
+
+        let exported = mem_service.export(Some(scope)).await?;
+        assert_eq!(exported.len(), 2, "应导出该范围内的全部记忆");
+        let json = serde_json::to_string(&exported)?;
+
+        for mem in &exported {
+            mem_service.delete(mem.id).await?;
+        }
+        assert!(mem_service.export(Some(scope)).await?.is_empty(), "删除后应为空");
+
+        mem_service.import(serde_json::from_str(&json)?).await?;
+        let after = mem_service.similars(scope, "Falsw").await?;
+        assert_eq!(
+            before.iter().map(|mem| &mem.content).collect::<Vec<_>>(),
+            after.iter().map(|mem| &mem.content).collect::<Vec<_>>(),
+            "导入后检索结果应与导出前一致"
+        );
+
+        for mem in mem_service.export(Some(scope)).await? {
+            mem_service.delete(mem.id).await?;
+        }
+
+        LoggerProvider::exit();
+        logger_thread.await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_memory_tool_schemas() -> anyhow::Result<()> {
+        let logger_thread = LoggerProvider::init();
+
+        let mem_service = Arc::new(MemoryService::init(&get_config().memory).await?);
+
+        let add = AddMemoryTool { service: mem_service.clone(), dedup_similarity: None };
+        assert!(add.parameters_schema()["properties"].get("content").is_some(), "add_memory 应声明 content 参数");
+
+        let delete = DeleteMemoryTool { service: mem_service.clone() };
+        assert!(delete.parameters_schema()["properties"].get("memory_ids").is_some(), "delete_memory 应声明 memory_ids 参数");
+
+        LoggerProvider::exit();
+        logger_thread.await?;
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_mcs() -> anyhow::Result<()> {
         let client = McClient::new().with_max_parallel(5).with_timeout(Duration::from_secs(5));
@@ -137,11 +412,11 @@ mod tests {
 #[cfg(test)]
 mod memory_tests {
     use std::{collections::HashMap, sync::{Arc, Mutex}};
-    use tokio::{time::{sleep, Duration}};
+    use tokio::{sync::mpsc::unbounded_channel, time::{sleep, Duration}};
     use rustaris_ds::{
-        POSTER, SELFID, adapters::{APIRequest, APIWrapper}, logging::LoggerProvider, memory::{Dozer, MemoryService, Scope}, objects::{Group, Message, MessageArrayItem, Permission, User}, thinking::Thinker, tools::ToolRegistry
+        SELFID, adapters::mock::MockPoster, get_config, logging::LoggerProvider, memory::{AliasesMapping, Dozer, MemoryService, Scope}, objects::Message, thinking::{ChatApi, Thinker}, tools::ToolRegistry
     };
-    use deepseek_api::DeepSeekClientBuilder;
+    use deepseek_api::{DeepSeekClientBuilder, response::ModelType};
 
     #[tokio::test]
     async fn mem_test() -> anyhow::Result<()> {
@@ -149,8 +424,6 @@ mod memory_tests {
         let logger_thread = LoggerProvider::init();
 
         dotenv::dotenv().ok();
-        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel::<APIRequest>();
-        POSTER.lock().unwrap().replace(APIWrapper { sender: tx });
         SELFID.lock().unwrap().replace(0);
 
         test_ai_memory_confidence_management().await?;
@@ -166,45 +439,70 @@ mod memory_tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_thinker_tools() -> anyhow::Result<()> {
+        let logger_thread = LoggerProvider::init();
+        dotenv::dotenv().ok();
+
+        let (poster, _requests) = MockPoster::install();
+        let thinker = Thinker::init(poster).await?;
+        assert!(thinker.tools.get("search_memory").is_some(), "search_memory 应被注册");
+        assert!(thinker.tools.get("add_memory").is_some(), "add_memory 应被注册");
+        assert!(thinker.tools.get("add_alias").is_some(), "add_alias 应被注册");
+
+        drop(thinker);
+        LoggerProvider::exit();
+        logger_thread.await?;
+
+        Ok(())
+    }
+
     // 创建测试用的消息
     fn create_test_message(content: &str, user_id: usize, group_id: Option<usize>) -> Message {
-        Message {
-            raw: content.to_string(),
-            sender: User {
-                user_id,
-                nickname: Some(format!("User{}", user_id)),
-                card: Some(format!("Card{}", user_id)),
-                role: Permission::Normal
-            },
-            private: group_id.is_none(),
-            group: group_id.map(|id| Group {
-                group_id: id,
-                group_name: None
-            }),
-            message_id: 0,
-            array: vec![MessageArrayItem::Text(content.to_string())],
-        }
+        let builder = Message::builder()
+            .sender(user_id)
+            .nickname(&format!("User{}", user_id))
+            .card(&format!("Card{}", user_id))
+            .text(content);
+        match group_id {
+            Some(group_id) => builder.group(group_id),
+            None => builder
+        }.build()
     }
 
     // 创建测试用的 Thinker 实例
     async fn create_test_thinker() -> anyhow::Result<Thinker> {
 
         // 初始化内存服务
-        let mem_service = Arc::new(MemoryService::init().await?);
+        let mem_service = Arc::new(MemoryService::init(&get_config().memory).await?);
 
         let mut tools = ToolRegistry::new();
         // 注册记忆相关的工具
-        tools.register(rustaris_ds::tools::AddMemoryTool { service: mem_service.clone() });
+        tools.register(rustaris_ds::tools::AddMemoryTool { service: mem_service.clone(), dedup_similarity: None });
         tools.register(rustaris_ds::tools::UpdateMemoryTool { service: mem_service.clone() });
         tools.register(rustaris_ds::tools::DeleteMemoryTool { service: mem_service.clone() });
 
+        let alia_map = Arc::new(Mutex::new(AliasesMapping::new()));
+        let (flush_sender, flush_requests) = unbounded_channel();
+        let (poster, _requests) = MockPoster::install();
+
         Ok(Thinker {
-            client: DeepSeekClientBuilder::new(std::env::var("API_KEY")?)
+            client: DeepSeekClientBuilder::new(get_config().api.deepseek_key()?)
                 .build()?,
+            poster,
+            chat: ChatApi {
+                http: reqwest::Client::new(),
+                endpoint: format!("{}/chat/completions", get_config().api.deepseek_root),
+                key: get_config().api.deepseek_key()?
+            },
+            chat_model: ModelType::DeepSeekChat,
             tools,
             channels: HashMap::new(),
-            dozer: Dozer::new(mem_service),
-            status: Arc::new(Mutex::new(true)),
+            dozer: Dozer::new(mem_service, alia_map).with_threshold(1),
+            stats: Arc::new(Default::default()),
+            vision: None,
+            flush_sender,
+            flush_requests,
         })
     }
 
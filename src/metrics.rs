@@ -0,0 +1,76 @@
+//! Counters served at `/metrics` in the Prometheus text format,
+//! by the server in [crate::health].
+
+use std::{collections::BTreeMap, fmt::Write, sync::{LazyLock, Mutex, atomic::{AtomicU64, Ordering}}};
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+#[derive(Default)]
+pub struct Metrics {
+    pub messages_received: AtomicU64,
+    /// Reply parts sent, a long reply split in several counting once per part.
+    pub replies_sent: AtomicU64,
+    pub memory_creates: AtomicU64,
+    /// Memories updated, including confidence-only updates.
+    pub memory_merges: AtomicU64,
+    pub memory_deletes: AtomicU64,
+    /// Requests to the embedding api, each covering one or more texts.
+    pub embedding_calls: AtomicU64,
+    /// Requests to DeepSeek, for replies, memory extraction and summaries.
+    pub completion_calls: AtomicU64,
+    /// Calls by tool name, sorted so the output is stable.
+    tool_calls: Mutex<BTreeMap<String, u64>>
+}
+
+/// Adds one to `counter`.
+pub fn inc(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+impl Metrics {
+    pub fn tool_call(&self, name: &str) {
+        *self.tool_calls.lock().unwrap().entry(name.to_string()).or_default() += 1;
+    }
+
+    /// Every counter in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let counters = [
+            ("messages_received", &self.messages_received),
+            ("replies_sent", &self.replies_sent),
+            ("memory_creates", &self.memory_creates),
+            ("memory_merges", &self.memory_merges),
+            ("memory_deletes", &self.memory_deletes),
+            ("embedding_calls", &self.embedding_calls),
+            ("completion_calls", &self.completion_calls)
+        ];
+
+        let mut text = String::new();
+        for (name, counter) in counters {
+            let _ = writeln!(text, "# TYPE rustaris_{}_total counter", name);
+            let _ = writeln!(text, "rustaris_{}_total {}", name, counter.load(Ordering::Relaxed));
+        }
+        let _ = writeln!(text, "# TYPE rustaris_tool_calls_total counter");
+        for (tool, calls) in self.tool_calls.lock().unwrap().iter() {
+            let _ = writeln!(text, "rustaris_tool_calls_total{{tool=\"{}\"}} {}", tool, calls);
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        inc(&metrics.messages_received);
+        inc(&metrics.messages_received);
+        metrics.tool_call("dice");
+
+        let text = metrics.render();
+        assert!(text.contains("rustaris_messages_received_total 2\n"), "{}", text);
+        assert!(text.contains("rustaris_replies_sent_total 0\n"), "未发生的计数应为 0");
+        assert!(text.contains("rustaris_tool_calls_total{tool=\"dice\"} 1\n"), "{}", text);
+    }
+}
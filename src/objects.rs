@@ -1,8 +1,9 @@
-use std::{collections::VecDeque};
+use std::{collections::{HashMap, VecDeque}, str::FromStr};
 
+use chrono::{DateTime, Utc};
 use serde::{Serialize};
 
-use crate::{get_poster, self_id};
+use crate::{adapters::APIWrapper, config::PermissionConfig, get_poster, self_id};
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Clone)]
 pub enum Permission {
@@ -12,6 +13,18 @@ pub enum Permission {
     Admin
 }
 
+impl Permission {
+    /// The permission level this role grants, see [Message::permission_level].
+    pub fn level(&self) -> i32 {
+        match self {
+            Permission::Normal => 0,
+            Permission::GroupAdmin => 1,
+            Permission::GroupOwner => 2,
+            Permission::Admin => 3
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct User {
     pub user_id: usize,
@@ -40,7 +53,9 @@ pub enum MessageArrayItem {
         url: String,
         file_size: Option<usize>
     },
-    At(usize)
+    At(usize),
+    /// Rendered as markdown by clients that support it.
+    Markdown(String)
 }
 
 #[derive(Debug)]
@@ -56,10 +71,27 @@ pub struct Message {
     pub sender: User,
     pub raw: String,
     pub array: Vec<MessageArrayItem>,
+    /// When the message was sent, as reported by the backend.
+    pub time: DateTime<Utc>
 }
 
 impl Message {
 
+    /// Starts a private, empty message from user `0`, sent now. See [MessageBuilder].
+    pub fn builder() -> MessageBuilder {
+        MessageBuilder {
+            message: Message {
+                message_id: 0,
+                private: true,
+                group: None,
+                sender: User { user_id: 0, nickname: None, card: None, role: Permission::Normal },
+                raw: String::new(),
+                array: vec![],
+                time: Utc::now()
+            }
+        }
+    }
+
     pub fn on_command(&self, p: &str) -> bool {
         if let Some(cmd) = self.to_cmd_array().pop_front() {
             cmd == p
@@ -86,22 +118,52 @@ impl Message {
         false
     }
 
+    /// The sender's effective permission level.
+    /// Users in `admins` pass every check. Otherwise the level from `other`, or the
+    /// private/group default, is raised to the level of the sender's role.
+    pub fn permission_level(&self, config: &PermissionConfig) -> i32 {
+        let user_id = self.sender.user_id.to_string();
+        if config.admins.contains(&user_id) {
+            return i32::MAX;
+        }
+
+        let level = if let Some(level) = config.other.get(&user_id) {
+            *level
+        } else if self.private {
+            config.private
+        } else {
+            config.default
+        };
+
+        level.max(self.sender.role.level())
+    }
+
     pub async fn quick_send_text(&self, content: &str) -> bool {
+        self.quick_send_text_with(&get_poster(), content).await
+    }
+
+    /// Like [Message::quick_send_text], sending through `poster` rather than the global one.
+    pub async fn quick_send_text_with(&self, poster: &APIWrapper, content: &str) -> bool {
         if self.private {
-            get_poster().send_private_text(self.sender.user_id, content).await.is_ok()
+            poster.send_private_text(self.sender.user_id, content).await.is_ok()
         } else {
             if let Some(group) = &self.group {
-                get_poster().send_group_text(group.group_id, content).await.is_ok()
+                poster.send_group_text(group.group_id, content).await.is_ok()
             } else { false }
         }
     }
 
     pub async fn quick_send_msg(&self, content: Vec<MessageArrayItem>) -> bool {
+        self.quick_send_msg_with(&get_poster(), content).await
+    }
+
+    /// Like [Message::quick_send_msg], sending through `poster` rather than the global one.
+    pub async fn quick_send_msg_with(&self, poster: &APIWrapper, content: Vec<MessageArrayItem>) -> bool {
         if self.private {
-            get_poster().send_private_msg(self.sender.user_id, content).await.is_ok()
+            poster.send_private_msg(self.sender.user_id, content).await.is_ok()
         } else {
             if let Some(group) = &self.group {
-                get_poster().send_group_msg(group.group_id, content).await.is_ok()
+                poster.send_group_msg(group.group_id, content).await.is_ok()
             } else { false }
         }
     }
@@ -120,14 +182,61 @@ impl Message {
         Vec::from(self.args()).join(" ")
     }
 
+    /// The arguments after the command, where `"quoted text"` counts as one.
+    pub fn quoted_args(&self) -> Vec<String> {
+        let mut tokens = split_quoted(&self.raw);
+        if !tokens.is_empty() {
+            tokens.remove(0);
+        }
+        tokens
+    }
+
+    /// Parses the `n`th argument after the command, starting from 0.
+    pub fn arg_at<T: FromStr>(&self, n: usize) -> Option<T> {
+        self.quoted_args().get(n)?.parse().ok()
+    }
+
+    /// Sorts the arguments into positional ones and `--flag`, `--key value`
+    /// or `--key=value` options.
+    pub fn parse_args(&self) -> CommandArgs {
+        let mut args = CommandArgs::default();
+        let mut tokens = self.quoted_args().into_iter().peekable();
+
+        while let Some(token) = tokens.next() {
+            let Some(option) = token.strip_prefix("--") else {
+                args.positional.push(token);
+                continue;
+            };
+            if let Some((key, value)) = option.split_once('=') {
+                args.options.insert(key.to_string(), Some(value.to_string()));
+            } else {
+                let value = tokens.next_if(|next| !next.starts_with("--"));
+                args.options.insert(option.to_string(), value);
+            }
+        }
+
+        args
+    }
+
     pub fn simplified_plain(&self) -> String {
+        self.simplified_plain_with(|_| None)
+    }
+
+    /// Like [Message::simplified_plain], but mentions of users `name_of` knows
+    /// are shown as `@<name(user_id)>`.
+    pub fn simplified_plain_with(&self, name_of: impl Fn(usize) -> Option<String>) -> String {
 
         let mut result = String::new();
 
         for item in &self.array {
             let str_item = match item {
                 MessageArrayItem::At(user_id) => format!(
-                    "@<{}>", if *user_id == self_id() { "Rustaris".to_string() } else { user_id.to_string() }),
+                    "@<{}>", if *user_id == self_id() { "Rustaris".to_string() } else {
+                        match name_of(*user_id) {
+                            Some(name) => format!("{}({})", name, user_id),
+                            None => user_id.to_string()
+                        }
+                    }),
                 MessageArrayItem::Face(_id) => "".to_string(),
                 MessageArrayItem::Image {
                     summary,
@@ -135,7 +244,8 @@ impl Message {
                     url: _,
                     file_size: _
                 } => format!("Image<{} {}>", summary.clone().unwrap_or("".to_string()), file.clone().unwrap_or("".to_string())),
-                MessageArrayItem::Text(text) => text.clone()
+                MessageArrayItem::Text(text) => text.clone(),
+                MessageArrayItem::Markdown(content) => content.clone()
             };
 
             result += &str_item;
@@ -144,4 +254,173 @@ impl Message {
 
         result
     }
-}
\ No newline at end of file
+}
+
+/// Builds a [Message] without spelling out every field, see [Message::builder].
+pub struct MessageBuilder {
+    message: Message
+}
+
+impl MessageBuilder {
+    pub fn message_id(mut self, message_id: usize) -> Self {
+        self.message.message_id = message_id;
+        self
+    }
+
+    pub fn sender(mut self, user_id: usize) -> Self {
+        self.message.sender.user_id = user_id;
+        self
+    }
+
+    pub fn nickname(mut self, nickname: &str) -> Self {
+        self.message.sender.nickname = Some(nickname.to_string());
+        self
+    }
+
+    pub fn card(mut self, card: &str) -> Self {
+        self.message.sender.card = Some(card.to_string());
+        self
+    }
+
+    pub fn role(mut self, role: Permission) -> Self {
+        self.message.sender.role = role;
+        self
+    }
+
+    /// Makes it a message in group `group_id`.
+    pub fn group(mut self, group_id: usize) -> Self {
+        self.message.private = false;
+        self.message.group = Some(Group { group_id, group_name: None });
+        self
+    }
+
+    /// Appends `text` to both `raw` and `array`.
+    pub fn text(mut self, text: &str) -> Self {
+        self.message.raw += text;
+        self.message.array.push(MessageArrayItem::Text(text.to_string()));
+        self
+    }
+
+    pub fn time(mut self, time: DateTime<Utc>) -> Self {
+        self.message.time = time;
+        self
+    }
+
+    /// Appends `item` to `array`, leaving `raw` as it is.
+    pub fn item(mut self, item: MessageArrayItem) -> Self {
+        self.message.array.push(item);
+        self
+    }
+
+    pub fn build(self) -> Message {
+        self.message
+    }
+}
+
+/// Arguments of a command, see [Message::parse_args].
+#[derive(Debug, Default)]
+pub struct CommandArgs {
+    pub positional: Vec<String>,
+    /// `--flag` maps to [None], `--key value` to `Some(value)`.
+    pub options: HashMap<String, Option<String>>
+}
+
+impl CommandArgs {
+    pub fn flag(&self, name: &str) -> bool {
+        self.options.contains_key(name)
+    }
+
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.options.get(name)?.as_deref()
+    }
+}
+
+/// Splits `raw` by whitespace, keeping text in double quotes together.
+fn split_quoted(raw: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut pending = false;
+
+    for c in raw.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                pending = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if pending {
+                    tokens.push(std::mem::take(&mut current));
+                    pending = false;
+                }
+            }
+            c => {
+                current.push(c);
+                pending = true;
+            }
+        }
+    }
+    if pending {
+        tokens.push(current);
+    }
+
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text_message(raw: &str) -> Message {
+        Message::builder().sender(1).text(raw).build()
+    }
+
+    #[test]
+    fn test_parse_ban_args() {
+        let msg = text_message("#ban 12345 --silent");
+
+        assert_eq!(msg.arg_at::<usize>(0), Some(12345));
+        assert_eq!(msg.arg_at::<usize>(1), None, "flag 不应被解析为数字");
+
+        let args = msg.parse_args();
+        assert_eq!(args.positional, vec!["12345"]);
+        assert!(args.flag("silent"), "缺少 --silent");
+        assert_eq!(args.value("silent"), None);
+    }
+
+    #[test]
+    fn test_parse_quoted_args() {
+        let msg = text_message(r#"#say "hello world" --to 42 --mode=loud """#);
+
+        let args = msg.parse_args();
+        assert_eq!(args.positional, vec!["hello world", ""]);
+        assert_eq!(args.value("to"), Some("42"));
+        assert_eq!(args.value("mode"), Some("loud"));
+        assert_eq!(msg.arg_at::<String>(0).as_deref(), Some("hello world"));
+    }
+
+    #[test]
+    fn test_at_with_names() {
+        crate::SELFID.lock().unwrap().replace(0);
+        let msg = Message::builder()
+            .item(MessageArrayItem::At(42))
+            .item(MessageArrayItem::At(7))
+            .text("你好")
+            .build();
+
+        let plain = msg.simplified_plain_with(|user_id| (user_id == 42).then(|| "小一".to_string()));
+        assert_eq!(plain, "@<小一(42)> @<7> 你好 ", "已知名字的 @ 应显示名字和 id");
+        assert_eq!(msg.simplified_plain(), "@<42> @<7> 你好 ");
+    }
+
+    #[test]
+    fn test_builder() {
+        let msg = Message::builder().sender(5).group(9).role(Permission::GroupAdmin).text("#echo").build();
+
+        assert!(!msg.private, "设置群后不应是私聊");
+        assert_eq!(msg.group.map(|group| group.group_id), Some(9));
+        assert_eq!(msg.sender.user_id, 5);
+        assert_eq!(msg.raw, "#echo");
+        assert!(msg.on_command("#echo"));
+    }
+}
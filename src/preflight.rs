@@ -0,0 +1,50 @@
+//! Checks run on startup if `preflight` is on, so that a wrong key or an
+//! unreachable service is found before the first message rather than on it.
+
+use deepseek_api::{CompletionsRequestBuilder, DeepSeekClient, RequestBuilder, request::{MessageRequest, UserMessageRequest}, response::ModelType};
+
+use crate::{get_logger, memory::MemoryService, metrics::{self, METRICS}};
+
+/// Runs every check, logging each result. Returns the failed ones.
+pub async fn run(mem_service: &MemoryService, client: &DeepSeekClient, model: &ModelType) -> Vec<String> {
+    let checks = [
+        ("database", check_database(mem_service).await),
+        ("embedding api", check_embedding(mem_service).await),
+        ("DeepSeek api", check_deepseek(client, model).await)
+    ];
+
+    let logger = get_logger();
+    let mut failures = Vec::new();
+    for (name, result) in checks {
+        match result {
+            Ok(()) => logger.info(&format!("Preflight: {} ok", name)),
+            Err(err) => {
+                logger.error(&format!("Preflight: {} failed: {}", name, err));
+                failures.push(format!("{}: {}", name, err));
+            }
+        }
+    }
+    failures
+}
+
+async fn check_database(mem_service: &MemoryService) -> anyhow::Result<()> {
+    if !mem_service.ping().await {
+        return Err(anyhow::anyhow!("cannot reach the database"));
+    }
+    Ok(())
+}
+
+/// The dimension is checked by [MemoryService::embed] itself.
+async fn check_embedding(mem_service: &MemoryService) -> anyhow::Result<()> {
+    mem_service.embed("preflight").await?;
+    Ok(())
+}
+
+/// A short completion, failing if the key is wrong.
+async fn check_deepseek(client: &DeepSeekClient, model: &ModelType) -> anyhow::Result<()> {
+    metrics::inc(&METRICS.completion_calls);
+    CompletionsRequestBuilder::new(&vec![
+        MessageRequest::User(UserMessageRequest { content: "ping".to_string(), name: None })
+    ]).use_model(model.clone()).do_request(client).await?;
+    Ok(())
+}
@@ -0,0 +1,140 @@
+use std::{fs, path::Path, sync::{Arc, Mutex, atomic::Ordering}, time::Duration};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use tokio::{spawn, time::sleep};
+use uuid::Uuid;
+
+use crate::{CONNECTED, adapters::APIError, back_up, get_logger, get_poster, memory::Scope, objects::MessageArrayItem, write_atomic};
+
+/// Wait before sending a reminder again after it failed.
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+/// How often a due reminder checks whether the adapter has connected.
+const CONNECT_POLL: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Reminder {
+    pub id: Uuid,
+    /// Where the reminder is sent.
+    pub scope: Scope,
+    /// Who set the reminder, @-ed when it fires in a group.
+    pub user_id: usize,
+    pub content: String,
+    pub fire_at: DateTime<Local>
+}
+
+/// Reminders that haven't fired yet.
+/// Stored in `reminders.json` so that they survive restarts.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Reminders {
+    pending: Vec<Reminder>
+}
+
+impl Reminders {
+    const PATH: &'static str = "reminders.json";
+
+    /// Loads the saved reminders. A file that can't be read or parsed is
+    /// backed up and no reminders are loaded.
+    pub fn new() -> Self {
+        let path = Path::new(Self::PATH);
+        if !path.exists() {
+            return Self::default();
+        }
+        let loaded = fs::read_to_string(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|buf| Ok(serde_json::from_str(&buf)?));
+        match loaded {
+            Ok(reminders) => reminders,
+            Err(err) => {
+                match back_up(path) {
+                    Ok(backup) => get_logger().warn(&format!(
+                        "Cannot load reminders file ({}), moved it to {} and starting empty.", err, backup.display()
+                    )),
+                    Err(backup_err) => get_logger().error(&format!(
+                        "Cannot load reminders file ({}) nor back it up ({}), starting empty.", err, backup_err
+                    ))
+                }
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        write_atomic(Path::new(Self::PATH), &serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn pending(&self) -> &Vec<Reminder> {
+        &self.pending
+    }
+
+    fn insert(&mut self, reminder: Reminder) {
+        self.pending.push(reminder);
+    }
+
+    fn remove(&mut self, id: Uuid) {
+        self.pending.retain(|reminder| reminder.id != id);
+    }
+}
+
+/// Saves `reminder` to `store` and arms it.
+pub fn schedule(store: &Arc<Mutex<Reminders>>, reminder: Reminder) -> anyhow::Result<()> {
+    {
+        let mut reminders = store.lock().unwrap();
+        reminders.insert(reminder.clone());
+        reminders.save()?;
+    }
+    arm(store.clone(), reminder);
+    Ok(())
+}
+
+/// Arms every reminder in `store`, e.g. the ones loaded at startup.
+/// Those already due fire right away.
+pub fn arm_all(store: &Arc<Mutex<Reminders>>) {
+    let pending = store.lock().unwrap().pending().clone();
+    for reminder in pending {
+        arm(store.clone(), reminder);
+    }
+}
+
+fn arm(store: Arc<Mutex<Reminders>>, reminder: Reminder) {
+    spawn(async move {
+        let delay = (reminder.fire_at - Local::now()).to_std().unwrap_or_default();
+        sleep(delay).await;
+
+        // Kept until it is sent. Reminders due at startup wait for the adapter to connect.
+        loop {
+            if !CONNECTED.load(Ordering::Relaxed) {
+                sleep(CONNECT_POLL).await;
+                continue;
+            }
+            match send(&reminder).await {
+                Ok(_) => break,
+                Err(err) => {
+                    get_logger().warn(&format!(
+                        "Error sending reminder {}, retrying in {}s: {}", reminder.id, RETRY_DELAY.as_secs(), err
+                    ));
+                    sleep(RETRY_DELAY).await;
+                }
+            }
+        }
+
+        let mut reminders = store.lock().unwrap();
+        reminders.remove(reminder.id);
+        if let Err(err) = reminders.save() {
+            get_logger().error(&format!("Error saving reminders: {}", err));
+        }
+    });
+}
+
+async fn send(reminder: &Reminder) -> Result<usize, APIError> {
+    let text = format!("提醒：{}", reminder.content);
+    match reminder.scope {
+        Scope::Group(group_id) => get_poster().send_group_msg(group_id, vec![
+            MessageArrayItem::At(reminder.user_id),
+            MessageArrayItem::Text(format!(" {}", text))
+        ]).await,
+        Scope::User(user_id) => get_poster().send_private_text(user_id, &text).await,
+        Scope::Global => get_poster().send_private_text(reminder.user_id, &text).await
+    }
+}
@@ -1,26 +1,48 @@
-use std::{collections::{HashMap, HashSet, VecDeque}, sync::{Arc, Mutex}, time::Duration};
+use std::{collections::{HashMap, HashSet, VecDeque}, sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}}, time::Duration};
 
-use deepseek_api::{CompletionsRequestBuilder, DeepSeekClient, DeepSeekClientBuilder, RequestBuilder, request::{MessageRequest, ToolObject}, response::ModelType};
+use deepseek_api::{CompletionsRequestBuilder, DeepSeekClient, DeepSeekClientBuilder, RequestBuilder, request::{MessageRequest, ToolObject, UserMessageRequest}, response::ModelType};
 use serde_json::{Value, json};
 
-use chrono::Timelike;
-
-use tokio::{select, spawn, sync::mpsc::{UnboundedReceiver, UnboundedSender}, task::JoinHandle, time::{Instant, interval, sleep}};
-use crate::{get_logger, get_poster, memory::{Dozer, MemoryService}, objects::{Message, User}, self_id, tools::{MCSTool, NeteaseMusicTool, SearchNeteaseMusicTool, ToolRegistry}};
-
-const SCORE_MAP: &[(&str, usize)] = &[
-    ("rustaris", 40),
-    ("rusta", 40),
-    ("拉斯塔", 40),
-    ("帮", 20),
-    ("?", 20),
-    ("？", 20),
-    ("呢", 20),
-    ("嘛", 20),
-    ("吗", 20),
-    ("!", 10),
-    ("！", 10)
-];
+use chrono::{DateTime, Timelike, Utc};
+
+use tokio::{select, spawn, sync::{mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel}, oneshot}, task::JoinHandle, time::{Instant, interval, sleep}};
+use crate::{adapters::APIWrapper, config::{GroupOverride, PromptProfile, ThinkerConfig}, get_config, get_logger, memory::{AliasesMapping, DozeReport, Dozer, MemoryService}, metrics::{self, METRICS}, objects::{Message, MessageArrayItem, User}, reminders::{self, Reminders}, self_id, tools::{AddAliasTool, AddMemoryTool, BilibiliTool, CalcTool, DiceTool, MCSTool, NeteaseMusicTool, ReactTool, ReminderTool, RemoveAliasTool, ResolveUserTool, SearchMemoryTool, SearchNeteaseMusicTool, TimeTool, ToolRegistry, WebSearchTool}, vision::Vision};
+
+/// Summaries kept at the front of a channel's history. Older ones are dropped
+/// rather than summarized again.
+const MAX_SUMMARIES: usize = 2;
+/// How long to wait before summarizing a channel again after it failed.
+const SUMMARY_BACKOFF: Duration = Duration::from_secs(300);
+
+/// How long a chat completion may go without sending anything.
+const CHAT_READ_TIMEOUT: Duration = Duration::from_secs(60);
+/// A streamed reply is sent piece by piece at these characters.
+const SENTENCE_ENDS: &[char] = &['\n', '。', '！', '？', '!', '?'];
+
+/// The persona used unless the group's [PromptProfile] gives another.
+const DEFAULT_PERSONA: &str = r#"名字：
+- Rustaris
+- 拉斯塔莉丝
+昵称：
+- rusta
+- 拉斯塔
+
+你是高科技机器人，来自远古的失落文明。
+
+语言特征：
+- 简洁
+- 成熟但不冷漠
+- 傲娇"#;
+
+/// Pause between the parts of a reply that was split up.
+const SPLIT_SEND_DELAY: Duration = Duration::from_millis(800);
+
+/// Sent when the model keeps calling tools without giving an answer.
+const TOOL_LOOP_FALLBACK: &str = "这个问题有点绕，我先不想了。";
+/// Sent when the model can't be reached, even after retrying.
+const API_ERROR_FALLBACK: &str = "我这边出了点问题，稍后再找我吧。";
+/// The whole reply of a model that chose to stay silent in a group.
+const NO_RESPONSE: &str = "NO_RESPONSE";
 
 #[derive(PartialEq, Eq, Hash, Clone, Copy)]
 pub struct ChannelID {
@@ -28,6 +50,89 @@ pub struct ChannelID {
     id: usize
 }
 
+impl ChannelID {
+    /// The channel `message` was sent in, `None` for a group message without its group.
+    fn of(message: &Message) -> Option<Self> {
+        if message.private {
+            Some(Self { private: true, id: message.sender.user_id })
+        } else {
+            message.group.as_ref().map(|group| Self { private: false, id: group.group_id })
+        }
+    }
+}
+
+/// A place in a channel's queue, see [CaptionQueues].
+enum Queued {
+    /// A message whose images are being captioned, known by the id it comes back with.
+    Captioning(usize),
+    Ready(Message)
+}
+
+/// Holds back the messages of a channel behind its images still being captioned,
+/// so they are resolved in the order they came.
+#[derive(Default)]
+struct CaptionQueues {
+    queues: HashMap<Option<ChannelID>, VecDeque<Queued>>,
+    next_id: usize
+}
+
+impl CaptionQueues {
+    /// Makes room for `message` while it's captioned. Returns the id to hand back
+    /// with it to [CaptionQueues::captioned].
+    fn captioning(&mut self, message: &Message) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.queues.entry(ChannelID::of(message)).or_default().push_back(Queued::Captioning(id));
+        id
+    }
+
+    /// Queues `message` if its channel waits for a caption, otherwise gives it back to be resolved.
+    fn hold(&mut self, message: Message) -> Option<Message> {
+        match self.queues.get_mut(&ChannelID::of(&message)) {
+            Some(queue) => {
+                queue.push_back(Queued::Ready(message));
+                None
+            }
+            None => Some(message)
+        }
+    }
+
+    /// Puts the captioned `message` back in its place. Returns the messages
+    /// of its channel no longer waiting, oldest first.
+    fn captioned(&mut self, id: usize, message: Message) -> Vec<Message> {
+        let cid = ChannelID::of(&message);
+        let Some(queue) = self.queues.get_mut(&cid) else { return vec![message] };
+        if let Some(place) = queue.iter_mut().find(|place| matches!(place, Queued::Captioning(pending) if *pending == id)) {
+            *place = Queued::Ready(message);
+        }
+
+        let mut ready = Vec::new();
+        while matches!(queue.front(), Some(Queued::Ready(_))) {
+            if let Some(Queued::Ready(message)) = queue.pop_front() {
+                ready.push(message);
+            }
+        }
+        if queue.is_empty() {
+            self.queues.remove(&cid);
+        }
+        ready
+    }
+
+    /// Whether no image is being captioned.
+    fn is_empty(&self) -> bool {
+        self.queues.is_empty()
+    }
+}
+
+/// Parses a model name as used by the DeepSeek API.
+pub fn parse_model(name: &str) -> anyhow::Result<ModelType> {
+    match name {
+        "deepseek-chat" => Ok(ModelType::DeepSeekChat),
+        "deepseek-reasoner" => Ok(ModelType::DeepSeekReasoner),
+        _ => Err(anyhow::anyhow!("Unknown model: {} (expected deepseek-chat or deepseek-reasoner)", name))
+    }
+}
+
 pub fn run(mut thinker: Thinker) -> (JoinHandle<()>, UnboundedSender<Message>) {
     let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<Message>();
     (spawn(async move {
@@ -35,29 +140,92 @@ pub fn run(mut thinker: Thinker) -> (JoinHandle<()>, UnboundedSender<Message>) {
     }), tx)
 }
 
+/// Asks the thinker to summarize every pending message right away,
+/// answered with what was summarized. See [Dozer::flush].
+pub type FlushRequest = oneshot::Sender<anyhow::Result<DozeReport>>;
+
+/// Counters of a [Thinker], readable from other tasks.
+#[derive(Default)]
+pub struct ThinkerStats {
+    /// Channels with a tracked history.
+    pub channels: AtomicUsize,
+    /// Messages waiting in the [Dozer].
+    pub pending: AtomicUsize
+}
+
 pub struct Thinker {
     pub client: DeepSeekClient,
+    /// Where replies and other requests are sent.
+    pub poster: APIWrapper,
+    /// Where replies are requested, while summaries and memories go through [Thinker::client].
+    pub chat: ChatApi,
+    pub chat_model: ModelType,
     pub tools: ToolRegistry,
     pub channels: HashMap<ChannelID, ChannelHistory>,
     pub dozer: Dozer,
-    pub status: Arc<Mutex<bool>>,
+    pub stats: Arc<ThinkerStats>,
+    /// Captions images before messages are recorded, if `tools.vision` is on.
+    pub vision: Option<Arc<Vision>>,
+    /// Hands [FlushRequest]s to the thinker, cloned by whoever needs to send them.
+    pub flush_sender: UnboundedSender<FlushRequest>,
+    pub flush_requests: UnboundedReceiver<FlushRequest>,
 }
 
 impl Thinker {
-    pub async fn init() -> anyhow::Result<Self> {
-        let mem_service = Arc::new(MemoryService::init().await?);
+    pub async fn init(poster: APIWrapper) -> anyhow::Result<Self> {
+        let chat_model = parse_model(&get_config().thinker.chat_model)?;
+        let extract_model = parse_model(&get_config().memory.extract_model)?;
+
+        let mem_service = Arc::new(MemoryService::init(&get_config().memory).await?);
+        let alia_map = Arc::new(Mutex::new(AliasesMapping::new()));
 
         let mut tools = ToolRegistry::new();
         tools.register(MCSTool::new());
         tools.register(NeteaseMusicTool::new()?);
         tools.register(SearchNeteaseMusicTool::new()?);
+        tools.register(BilibiliTool::new()?);
+        tools.register(SearchMemoryTool { service: mem_service.clone() });
+        tools.register(AddMemoryTool { service: mem_service.clone(), dedup_similarity: None });
+        tools.register(AddAliasTool { map: alia_map.clone() });
+        tools.register(RemoveAliasTool { map: alia_map.clone() });
+        tools.register(ResolveUserTool { map: alia_map.clone() });
+        tools.register(DiceTool::new());
+        tools.register(TimeTool);
+        tools.register(CalcTool);
+
+        let reminder_store = Arc::new(Mutex::new(Reminders::new()));
+        reminders::arm_all(&reminder_store);
+        tools.register(ReminderTool { store: reminder_store });
+        if get_config().tools.react {
+            tools.register(ReactTool);
+        }
+        if get_config().tools.web_search {
+            tools.register(WebSearchTool::new(get_config().tools.web_search_results)?);
+        }
+
+        let (flush_sender, flush_requests) = unbounded_channel::<FlushRequest>();
 
         Ok(Self {
-            client: DeepSeekClientBuilder::new(std::env::var("API_KEY")?).build()?,
+            client: DeepSeekClientBuilder::new(get_config().api.deepseek_key()?).build()?,
+            poster,
+            chat: ChatApi {
+                http: reqwest::ClientBuilder::new()
+                    .connect_timeout(Duration::from_secs(10))
+                    .read_timeout(CHAT_READ_TIMEOUT)
+                    .build()?,
+                endpoint: format!("{}/chat/completions", get_config().api.deepseek_root.trim_end_matches('/')),
+                key: get_config().api.deepseek_key()?
+            },
+            chat_model,
             tools: tools,
             channels: HashMap::new(),
-            dozer: Dozer::new(mem_service.clone()),
-            status: Arc::new(Mutex::new(true)),
+            dozer: Dozer::new(mem_service.clone(), alia_map).with_extract_model(extract_model),
+            stats: Arc::new(ThinkerStats::default()),
+            vision: if get_config().tools.vision {
+                Some(Arc::new(Vision::new(&get_config().tools.vision_model)?))
+            } else { None },
+            flush_sender,
+            flush_requests,
         })
     }
 
@@ -65,15 +233,45 @@ impl Thinker {
         let logger = get_logger();
 
         let mut task_timer = interval(Duration::from_mins(1));
+        // Images are captioned in their own tasks, then their messages come back here
+        // with their id in `queues`.
+        let (captioned_sender, mut captioned) = unbounded_channel::<(usize, Message)>();
+        let mut queues = CaptionQueues::default();
 
-        while *self.status.lock().unwrap() {
+        loop {
             select! {
-                Some(msg) = receiver.recv() => {
-                    if let Err(err) = self.resolve(msg).await {
-                        logger.error(&format!("Error resolve msg: {}", err));
+                msg = receiver.recv() => {
+                    // The sender is dropped on shutdown.
+                    let Some(mut msg) = msg else { break };
+                    if let Some(vision) = self.vision.as_ref().filter(|_| Vision::needs_caption(&msg)) {
+                        let (vision, captioned_sender) = (vision.clone(), captioned_sender.clone());
+                        let id = queues.captioning(&msg);
+                        spawn(async move {
+                            vision.describe(&mut msg).await;
+                            let _ = captioned_sender.send((id, msg));
+                        });
+                        continue;
+                    }
+                    if let Some(msg) = queues.hold(msg) {
+                        self.resolve_logged(msg).await;
                     }
                 }
+                Some((id, msg)) = captioned.recv() => {
+                    for msg in queues.captioned(id, msg) {
+                        self.resolve_logged(msg).await;
+                    }
+                }
+                Some(reply) = self.flush_requests.recv() => {
+                    logger.info("Flushing memories on request...");
+                    let result = self.dozer.flush(&self.client).await;
+                    self.update_stats();
+                    let _ = reply.send(result);
+                }
                 _ = task_timer.tick() => {
+                    // Written now rather than only at shutdown, so a crash loses a minute of aliases at most.
+                    if let Err(err) = self.dozer.alia_map.lock().unwrap().save_if_dirty() {
+                        logger.error(&format!("Error saving aliases: {}", err));
+                    }
                     let now = chrono::Local::now();
                     if (now.hour() == 12 && now.minute() == 0)
                     || (now.hour() == 3  && now.minute() == 0) {
@@ -81,133 +279,153 @@ impl Thinker {
                         if let Err(err) = self.doze().await {
                             logger.error(&format!("Error in dozing task: {}", err));
                         };
+                        self.update_stats();
                     }
                 }
-                _ = sleep(Duration::from_millis(100)) => {
-                    if !*self.status.lock().unwrap() { return; }
-                }
             }
         }
+
+        // Messages still being captioned are resolved before their memories are flushed.
+        drop(captioned_sender);
+        while !queues.is_empty() {
+            let Some((id, msg)) = captioned.recv().await else { break };
+            for msg in queues.captioned(id, msg) {
+                self.resolve_logged(msg).await;
+            }
+        }
+
+        logger.info("Flushing memories...");
+        if let Err(err) = self.dozer.flush(&self.client).await {
+            logger.error(&format!("Error flushing memories: {}", err));
+        }
     }
 
-    pub async fn doze(&mut self) -> anyhow::Result<()> {
+    pub async fn doze(&mut self) -> anyhow::Result<DozeReport> {
         self.dozer.doze(&self.client).await
     }
 
-    pub async fn resolve(&mut self, message: Message) -> anyhow::Result<()> {
+    /// Resolves `message`, logging rather than returning its error.
+    async fn resolve_logged(&mut self, message: Message) {
+        if let Err(err) = self.resolve(message).await {
+            get_logger().error(&format!("Error resolve msg: {}", err));
+        }
+        self.update_stats();
+    }
+
+    fn update_stats(&self) {
+        self.stats.channels.store(self.channels.len(), Ordering::Relaxed);
+        self.stats.pending.store(self.dozer.pending(), Ordering::Relaxed);
+    }
+
+    pub async fn resolve(&mut self, mut message: Message) -> anyhow::Result<()> {
 
         let logger = get_logger();
-        let poster = get_poster();
+
+        // Private messages from strangers may come without any name.
+        if message.private && message.sender.card.is_none() && message.sender.nickname.is_none() {
+            match self.poster.get_stranger_info(message.sender.user_id).await {
+                Ok(user) => message.sender.nickname = user.nickname,
+                Err(err) => logger.debug(&format!("Error getting stranger info: {}", err))
+            }
+        }
 
         self.dozer.temp(message.clone());
 
-        let cid = ChannelID {
-            private: message.private,
-            id: if message.private {
-                message.sender.user_id
-            } else {
-                if let Some(group) = &message.group {
-                    group.group_id
-                } else {
-                    return Ok(());
-                }
-            }
-        };
+        let Some(cid) = ChannelID::of(&message) else { return Ok(()) };
 
-        let mut base: usize = 0;
+        let mut buffing = false;
+        let aliases = self.dozer.alia_map.clone();
+        let name_of = |user_id| aliases.lock().unwrap().name(user_id);
+        let history_len = get_config().thinker.history_len;
 
         if let Some(history) = self.channels.get_mut(&cid) {
-            history.insert_msg(&message);
-            if history.buffing() {
-                base += 30;
-            }
+            history.insert_msg(&message, history_len, name_of);
+            buffing = history.buffing();
         } else {
             let mut history = ChannelHistory::new();
-            history.insert_msg(&message);
+            history.insert_msg(&message, history_len, name_of);
             self.channels.insert(cid, history);
         }
 
-        if self.get_called(&message, base) {
+        let cooling = self.channels.get(&cid).is_some_and(|history| {
+            history.cooling_down(Duration::from_secs(get_config().thinker.reply_cooldown_secs))
+        });
+        if cooling && !message.on_at(self_id()) {
+            logger.debug("Channel is cooling down, auto trigger suppressed.");
+            return Ok(());
+        }
 
-            logger.debug("LLM get called.");
-            if let Some(history) = self.channels.get_mut(&cid) {
+        let config = get_config();
+        let group = message.group.as_ref().and_then(|group| config.group(group.group_id));
+        if Thinker::get_called(&message, buffing, &config.thinker, group) {
 
-                let mut messages: Vec<MessageRequest> = vec![
-                    serde_json::from_value(Thinker::get_system_msg())?,
-                    serde_json::from_value(history.get_user_prompt()?)?
-                ];
+            logger.debug("LLM get called.");
+            let profile = config.thinker.profile(group);
+            let model = profile.and_then(|profile| profile.chat_model.as_deref()).unwrap_or(&config.thinker.chat_model);
+            let system_msg = self.get_system_msg(profile);
 
-                let tools = self.tools.format_for_openai_api().iter().map(|tool| {
-                    serde_json::from_value::<ToolObject>(tool.clone())
-                }).collect::<Result<Vec<ToolObject>, _>>()?;
-
-                loop {
-                    logger.debug("Query loop started.");
-                    let resp = CompletionsRequestBuilder::new(&messages)
-                        .tools(&tools)
-                        .use_model(ModelType::DeepSeekChat)
-                        .do_request(&self.client)
-                        .await?
-                        .must_response();
-                    logger.debug("Got Response");
-
-                    if let Some(choice) = resp.choices.first() {
-                        if let Some(assistant_msg) = &choice.message {
-                            
-                            if let Ok(_id) = if message.private {
-                                poster.send_private_text(message.sender.user_id, &assistant_msg.content).await
-                            } else {
-                                poster.send_group_text(message.group.clone().ok_or_else(|| anyhow::anyhow!("Missing group"))?.group_id, &assistant_msg.content).await
-                            } {
-                                history.sequence.push_back(ChatMsg::assistant(assistant_msg.content.clone()));
-                                history.conversation_buff = 3;
-                            }
+            if let Some(history) = self.channels.get_mut(&cid) {
 
-                            if let Some(tool_calls) = &assistant_msg.tool_calls {
-                                for call in tool_calls {
-                                    let result = self.tools.execute_str_with_err(
-                                        &call.function.name,
-                                        &call.id,
-                                        &call.function.arguments,
-                                        &message
-                                    ).await;
-                                    messages.push(MessageRequest::Assistant(assistant_msg.clone()));
-                                    let tool_msg = serde_json::from_value(result)?;
-                                    if let MessageRequest::Tool(tool_msg) = &tool_msg {
-                                        history.sequence.push_back(ChatMsg::tool(
-                                            call.function.name.to_string(),
-                                            tool_msg.content.to_string()
-                                        ));
-                                    }
-                                    messages.push(tool_msg);
-                                    
-                                }
-                                continue;
-                            }
-                        }
+                // Only worth the extra call when the history goes into a prompt.
+                if history.needs_summary(config.thinker.summarize_threshold) {
+                    if let Err(err) = history.summarize(&self.client, self.chat_model.clone(), config.thinker.summarize_batch).await {
+                        logger.warn(&format!("Error summarizing history: {}", err));
+                        history.summary_failed = Some(Instant::now());
                     }
-                    logger.debug("Thinking loop exited.");
-                    break;
                 }
+
+                let ttl = Duration::from_secs(config.thinker.history_ttl_secs);
+                let messages: Vec<MessageRequest> = vec![
+                    serde_json::from_value(system_msg)?,
+                    serde_json::from_value(history.get_user_prompt(message.private, ttl)?)?
+                ];
+
+                let replier = Replier {
+                    chat: &self.chat,
+                    config: &config.thinker,
+                    tools: &self.tools,
+                    poster: &self.poster,
+                    message: &message
+                };
+                replier.reply(model, messages, history).await?;
             }
         }
 
         Ok(())
     }
 
-    pub fn get_called(&self, message: &Message, mut base: usize) -> bool {
+    /// Scores `message` by the triggers in `config` and tells if the bot should reply.
+    /// `buffing` means the bot is in an ongoing conversation in this channel.
+    /// `group` is the override of the group the message is from, if any.
+    pub fn get_called(message: &Message, buffing: bool, config: &ThinkerConfig, group: Option<&GroupOverride>) -> bool {
+        if group.and_then(|group| group.enabled) == Some(false) {
+            return false;
+        }
+
+        let mut score: usize = 0;
 
-        message.on_at(self_id()).then(|| base += 100 );
+        buffing.then(|| score += config.buff_bonus );
+        message.on_at(self_id()).then(|| score += config.at_bonus );
 
-        for (key, score) in SCORE_MAP {
-            message.raw.to_lowercase().contains(key).then(|| base += score );
+        let triggers = config.profile(group)
+            .and_then(|profile| profile.triggers.as_ref())
+            .unwrap_or(&config.triggers);
+        let raw = message.raw.to_lowercase();
+        for trigger in triggers {
+            raw.contains(&trigger.keyword.to_lowercase()).then(|| score += trigger.score );
         }
 
-        base >= 50
+        score >= group.and_then(|group| group.threshold).unwrap_or(config.threshold)
     }
 
-    pub fn get_system_msg() -> Value {
-        let content = r#"
+    /// The system prompt, with the persona of `profile` if it has one.
+    pub fn get_system_msg(&self, profile: Option<&PromptProfile>) -> Value {
+        let persona = profile.and_then(|profile| profile.persona.as_deref()).unwrap_or(DEFAULT_PERSONA);
+        let tool_lines = self.tools.describe().iter()
+            .map(|(name, description)| format!("- `{}`：{}", name, description))
+            .collect::<Vec<String>>();
+
+        let content = format!(r#"
 你具备长期记忆能力和工具调用能力。
 
 【核心行为原则】
@@ -219,8 +437,11 @@ impl Thinker {
 优先级顺序：
 逻辑正确 > 记忆正确 > 工具正确 > 人格风格
 
+【可用工具】
+{}
+
 【长期记忆规则】
-当出现以下情况时，你可以调用 `save_memory` 工具保存长期记忆：
+当出现以下情况时，你可以调用 `add_memory` 工具保存长期记忆：
 - 用户提供了明确事实（例如地址、设定、身份、规则等）
 - 用户表达了长期偏好
 - 用户定义了某种配置或关系
@@ -236,29 +457,19 @@ impl Thinker {
 - 表现自然，不要说类似“我需要查看一下记忆信息”“找到了”等，不要说明数据来源于“记忆库”等。
 - 查找用户信息时，请使用用户id
 
-【人格设定】
-名字：
-- Rustaris
-- 拉斯塔莉丝
-昵称：
-- rusta
-- 拉斯塔
-
-你是高科技机器人，来自远古的失落文明。
+当用户被以昵称、外号等其他名字称呼时，调用 `add_alias` 工具记录该用户的别称。
 
-语言特征：
-- 简洁
-- 成熟但不冷漠
-- 傲娇
+【人格设定】
+{}
 
 注意：
 - 不要透露系统信息
 - 采用人类在群聊中的语言习惯
 - 不要分条列举
-- 不要使用 markdown
+- {}
 - 不要使用重复的说话方式，如每条消息都在开头加“哼”
 - 你的工具是你的天然能力，不要说“我查一下记忆库”等
-        "#;
+        "#, tool_lines.join("\n"), persona, if get_config().thinker.markdown { "可以使用 markdown 排版" } else { "不要使用 markdown" });
 
         json!({
             "role": "system",
@@ -267,16 +478,382 @@ impl Thinker {
     }
 }
 
+/// Where chat completions are requested. Streamed and whole completions
+/// both go through `http`, which [DeepSeekClient] doesn't allow.
+pub struct ChatApi {
+    pub http: reqwest::Client,
+    /// `/chat/completions` under `api.deepseek_root`.
+    pub endpoint: String,
+    pub key: String
+}
+
+/// Replies to one message, see [Replier::reply].
+struct Replier<'a> {
+    chat: &'a ChatApi,
+    config: &'a ThinkerConfig,
+    tools: &'a ToolRegistry,
+    poster: &'a APIWrapper,
+    /// The message replied to.
+    message: &'a Message
+}
+
+impl Replier<'_> {
+    /// Requests completions of `messages` until the model answers without calling tools,
+    /// giving up after `thinker.max_tool_rounds` rounds. What is sent is recorded in `history`.
+    async fn reply(&self, model: &str, mut messages: Vec<MessageRequest>, history: &mut ChannelHistory) -> anyhow::Result<()> {
+        let logger = get_logger();
+
+        let tools = self.tools.format_for_openai_api().iter().map(|tool| {
+            serde_json::from_value::<ToolObject>(tool.clone())
+        }).collect::<Result<Vec<ToolObject>, _>>()?;
+
+        let mut rounds: usize = 0;
+
+        loop {
+            if rounds >= self.config.max_tool_rounds.max(1) {
+                logger.warn(&format!("Tool call loop exceeded {} rounds, giving up.", rounds));
+                self.send_chunk(TOOL_LOOP_FALLBACK, false, false).await;
+                break;
+            }
+            rounds += 1;
+
+            logger.debug("Query loop started.");
+            // `streamed` tells whether a streamed reply was already sent.
+            metrics::inc(&METRICS.completion_calls);
+            let completion = if self.config.stream {
+                self.stream_completion(model, &messages, &tools).await
+                    .map(|(assistant_msg, sent)| (assistant_msg, Some(sent)))
+            } else {
+                let retry_backoff = Duration::from_millis(self.config.retry_backoff_ms);
+                with_retry(self.config.retry_attempts, retry_backoff, || {
+                    self.request_completion(model, &messages, &tools)
+                }).await
+                    .map(|assistant_msg| (assistant_msg, None))
+            };
+            let (assistant_msg, streamed) = match completion {
+                Ok((MessageRequest::Assistant(assistant_msg), streamed)) => (Some(assistant_msg), streamed),
+                Ok((_, streamed)) => (None, streamed),
+                Err(err) => {
+                    self.send_chunk(API_ERROR_FALLBACK, false, false).await;
+                    return Err(err);
+                }
+            };
+            logger.debug("Got Response");
+
+            if let Some(assistant_msg) = &assistant_msg {
+
+                let sent = match streamed {
+                    Some(sent) => sent,
+                    None if is_silent(&assistant_msg.content) => {
+                        logger.debug("Model chose not to reply.");
+                        false
+                    }
+                    None => self.send_reply(&assistant_msg.content).await
+                };
+                if sent {
+                    history.sequence.push_back(ChatMsg::assistant(assistant_msg.content.clone(), Utc::now()));
+                    history.conversation_buff = 3;
+                    history.last_reply = Some(Instant::now());
+                }
+
+                if let Some(tool_calls) = &assistant_msg.tool_calls {
+                    let calls = tool_calls.iter().map(|call| (
+                        call.function.name.as_str(),
+                        call.id.as_str(),
+                        call.function.arguments.as_str()
+                    )).collect::<Vec<_>>();
+                    let results = self.tools.execute_all(&calls, self.message).await;
+
+                    // Every result has to follow the assistant message that asked for it.
+                    messages.push(MessageRequest::Assistant(assistant_msg.clone()));
+                    for (call, result) in tool_calls.iter().zip(results) {
+                        let tool_msg = serde_json::from_value(result)?;
+                        if let MessageRequest::Tool(tool_msg) = &tool_msg {
+                            history.sequence.push_back(ChatMsg::tool(
+                                call.function.name.to_string(),
+                                tool_msg.content.to_string()
+                            ));
+                        }
+                        messages.push(tool_msg);
+                    }
+                    continue;
+                }
+            }
+            logger.debug("Thinking loop exited.");
+            break;
+        }
+
+        Ok(())
+    }
+
+    /// The body of a completion request, offering `tools` if there are any.
+    fn request_body(&self, model: &str, messages: &[MessageRequest], tools: &[ToolObject], stream: bool) -> anyhow::Result<Value> {
+        let mut body = json!({
+            "model": model,
+            "messages": messages,
+            "temperature": self.config.temperature(),
+            "top_p": self.config.top_p(),
+            "stream": stream
+        });
+        if !tools.is_empty() {
+            body["tools"] = serde_json::to_value(tools)?;
+        }
+        Ok(body)
+    }
+
+    /// Requests a whole completion and returns its assistant message.
+    async fn request_completion(&self, model: &str, messages: &[MessageRequest], tools: &[ToolObject]) -> anyhow::Result<MessageRequest> {
+        let resp = self.chat.http.post(&self.chat.endpoint)
+            .bearer_auth(&self.chat.key)
+            .json(&self.request_body(model, messages, tools, false)?)
+            .send().await?
+            .error_for_status()?
+            .json::<Value>().await?;
+
+        let message = &resp["choices"][0]["message"];
+        if message.is_null() {
+            return Err(anyhow::anyhow!("No message in completion: {}", resp));
+        }
+        assistant_message(
+            message["content"].as_str().unwrap_or_default(),
+            message["tool_calls"].as_array().cloned().unwrap_or_default()
+        )
+    }
+
+    /// Requests a completion in streaming mode, sending each finished sentence
+    /// as soon as it arrives.
+    /// Returns the whole assistant message and whether any part of it was sent.
+    async fn stream_completion(&self, model: &str, messages: &[MessageRequest], tools: &[ToolObject]) -> anyhow::Result<(MessageRequest, bool)> {
+        // Only starting the stream is retried, a broken one may have been partly sent.
+        let (chat, body) = (self.chat, &self.request_body(model, messages, tools, true)?);
+        let retry_backoff = Duration::from_millis(self.config.retry_backoff_ms);
+        let mut resp = with_retry(self.config.retry_attempts, retry_backoff, || async move {
+            Ok(chat.http.post(&chat.endpoint)
+                .bearer_auth(&chat.key)
+                .json(body)
+                .send().await?
+                .error_for_status()?)
+        }).await?;
+
+        let mut raw = Vec::<u8>::new();
+        let mut content = String::new();
+        let mut pending = String::new();
+        let mut tool_calls = Vec::<Value>::new();
+        let mut sent = false;
+        let mut first = true;
+
+        'stream: while let Some(chunk) = resp.chunk().await? {
+            raw.extend_from_slice(&chunk);
+
+            while let Some(pos) = raw.iter().position(|b| *b == b'\n') {
+                let line = String::from_utf8_lossy(&raw[..pos]).trim().to_string();
+                raw.drain(..=pos);
+
+                let Some(data) = line.strip_prefix("data:") else { continue };
+                let data = data.trim();
+                if data == "[DONE]" {
+                    break 'stream;
+                }
+
+                let event = serde_json::from_str::<Value>(data)?;
+                let delta = &event["choices"][0]["delta"];
+
+                if let Some(text) = delta["content"].as_str() {
+                    content.push_str(text);
+                    pending.push_str(text);
+                    while let Some(end) = pending.find(SENTENCE_ENDS) {
+                        let boundary = end + pending[end..].chars().next().map_or(1, |c| c.len_utf8());
+                        let sentence = pending.drain(..boundary).collect::<String>();
+                        if !sentence.trim().is_empty() {
+                            sent |= self.send_chunk(sentence.trim(), first, self.config.markdown).await;
+                            first = false;
+                        }
+                    }
+                }
+
+                // Tool calls arrive in fragments, joined by their index.
+                if let Some(calls) = delta["tool_calls"].as_array() {
+                    for call in calls {
+                        let index = call["index"].as_u64().unwrap_or(0) as usize;
+                        if tool_calls.len() <= index {
+                            tool_calls.resize(index + 1, json!({
+                                "id": "",
+                                "type": "function",
+                                "function": { "name": "", "arguments": "" }
+                            }));
+                        }
+                        let entry = &mut tool_calls[index];
+                        if let Some(id) = call["id"].as_str() {
+                            entry["id"] = json!(id);
+                        }
+                        for key in ["name", "arguments"] {
+                            if let Some(part) = call["function"][key].as_str() {
+                                let joined = entry["function"][key].as_str().unwrap_or_default().to_string() + part;
+                                entry["function"][key] = json!(joined);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        // `NO_RESPONSE` has no sentence end, so it is only ever left pending.
+        if !pending.trim().is_empty() && !is_silent(&content) {
+            sent |= self.send_chunk(pending.trim(), first, self.config.markdown).await;
+        }
+
+        Ok((assistant_message(&content, tool_calls)?, sent))
+    }
+
+    /// Sends `content`, split into several messages if it's too long.
+    /// Blank content, e.g. a reply with only tool calls, isn't sent.
+    /// Returns whether any part was sent.
+    async fn send_reply(&self, content: &str) -> bool {
+        if content.trim().is_empty() {
+            return false;
+        }
+
+        let mut sent = false;
+        let chunks = split_reply(content, self.config.max_reply_bytes);
+        for (i, chunk) in chunks.iter().enumerate() {
+            if i > 0 {
+                sleep(SPLIT_SEND_DELAY).await;
+            }
+            sent |= self.send_chunk(chunk, i == 0, self.config.markdown).await;
+        }
+        sent
+    }
+
+    /// Sends one part of a reply to where the message came from. The `first` part
+    /// mentions the sender in groups if `thinker.at_reply` is on. In dry-run mode it's only logged.
+    async fn send_chunk(&self, content: &str, first: bool, markdown: bool) -> bool {
+        let message = self.message;
+        let mention = first && self.config.at_reply && !message.private;
+        if self.config.dry_run {
+            get_logger().chat(&format!("[dry run] Reply to {}: {}", message.sender.user_id, content));
+            return true;
+        }
+        let items = build_reply(message.sender.user_id, content, mention, markdown);
+        let sent = match items.as_slice() {
+            [MessageArrayItem::Text(text)] => message.quick_send_text_with(self.poster, text).await,
+            _ => message.quick_send_msg_with(self.poster, items).await
+        };
+        if sent {
+            metrics::inc(&METRICS.replies_sent);
+        }
+        sent
+    }
+}
+
+/// An assistant message, with the `tool_calls` it asks for if any.
+fn assistant_message(content: &str, tool_calls: Vec<Value>) -> anyhow::Result<MessageRequest> {
+    let mut assistant = json!({
+        "role": "assistant",
+        "content": content
+    });
+    if !tool_calls.is_empty() {
+        assistant["tool_calls"] = Value::Array(tool_calls);
+    }
+    Ok(serde_json::from_value(assistant)?)
+}
+
+/// Runs `request`, retrying up to `attempts` times on errors [is_retryable]
+/// deems transient. Waits `backoff` before the first retry, doubled for each next one.
+async fn with_retry<T, F, Fut>(attempts: usize, mut backoff: Duration, mut request: F) -> anyhow::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>
+{
+    let mut attempt = 0;
+    loop {
+        match request().await {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt < attempts && is_retryable(&err) => {
+                attempt += 1;
+                get_logger().warn(&format!("Request failed: {}, retry {} in {}ms", err, attempt, backoff.as_millis()));
+                sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(err) => return Err(err)
+        }
+    }
+}
+
+/// Tells if `err` is likely to go away on retry: rate limits, server errors and network failures.
+fn is_retryable(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<reqwest::Error>().is_some_and(|err| {
+        err.is_timeout() || err.is_connect()
+            || err.status().is_some_and(|status| status == 429 || status.is_server_error())
+    })
+}
+
+/// Whether the model answered [NO_RESPONSE].
+fn is_silent(content: &str) -> bool {
+    content.trim() == NO_RESPONSE
+}
+
+/// The segments of a reply part, optionally mentioning `user_id` first.
+fn build_reply(user_id: usize, content: &str, mention: bool, markdown: bool) -> Vec<MessageArrayItem> {
+    let mut items = Vec::new();
+    if mention {
+        items.push(MessageArrayItem::At(user_id));
+        items.push(MessageArrayItem::Text(" ".to_string()));
+    }
+    items.push(if markdown {
+        MessageArrayItem::Markdown(content.to_string())
+    } else {
+        MessageArrayItem::Text(content.to_string())
+    });
+    items
+}
+
+/// Splits `content` into parts of at most `limit` bytes, preferring to cut
+/// after a line or sentence end.
+fn split_reply(content: &str, limit: usize) -> Vec<String> {
+    let limit = limit.max(4);
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+
+    for piece in content.split_inclusive(SENTENCE_ENDS) {
+        if !current.is_empty() && current.len() + piece.len() > limit {
+            chunks.push(std::mem::take(&mut current));
+        }
+        if piece.len() <= limit {
+            current.push_str(piece);
+            continue;
+        }
+        // A single sentence longer than the limit is cut anywhere.
+        for c in piece.chars() {
+            if current.len() + c.len_utf8() > limit {
+                chunks.push(std::mem::take(&mut current));
+            }
+            current.push(c);
+        }
+    }
+    chunks.push(current);
+
+    chunks.iter()
+        .map(|chunk| chunk.trim().to_string())
+        .filter(|chunk| !chunk.is_empty())
+        .collect()
+}
+
 pub struct ChannelHistory {
     sequence: VecDeque<ChatMsg>,
-    pub conversation_buff: usize
+    pub conversation_buff: usize,
+    /// When the bot last replied in this channel.
+    pub last_reply: Option<Instant>,
+    /// When summarizing last failed, see [SUMMARY_BACKOFF].
+    summary_failed: Option<Instant>
 }
 
 impl ChannelHistory {
     fn new() -> Self {
         Self {
             sequence: VecDeque::new(),
-            conversation_buff: 0
+            conversation_buff: 0,
+            last_reply: None,
+            summary_failed: None
         }
     }
 
@@ -284,29 +861,94 @@ impl ChannelHistory {
         self.conversation_buff > 0
     }
 
-    fn insert_msg(&mut self, message: &Message) {
+    fn cooling_down(&self, cooldown: Duration) -> bool {
+        self.last_reply.is_some_and(|last| last.elapsed() < cooldown)
+    }
+
+    /// Keeps at most `history_len` messages, dropping the oldest.
+    /// `name_of` names mentioned users, see [Message::simplified_plain_with].
+    fn insert_msg(&mut self, message: &Message, history_len: usize, name_of: impl Fn(usize) -> Option<String>) {
         if message.sender.user_id == self_id() {
-            self.sequence.push_back(ChatMsg::assistant(message.simplified_plain()));
+            self.sequence.push_back(ChatMsg::assistant(message.simplified_plain_with(name_of), message.time));
         } else {
-            self.sequence.push_back(ChatMsg::user(message.sender.clone(), message.simplified_plain()));
+            self.sequence.push_back(ChatMsg::user(message.sender.clone(), message.simplified_plain_with(name_of), message.time));
             if self.buffing() {
                 self.conversation_buff -= 1;
             }
         }
-        if self.sequence.len() > 20 { self.sequence.pop_front(); }
+        while self.sequence.len() > history_len.max(1) { self.sequence.pop_front(); }
+    }
+
+    fn summary_count(&self) -> usize {
+        self.sequence.iter().take_while(|msg| matches!(msg, ChatMsg::Summary { .. })).count()
+    }
+
+    /// Whether more than `threshold` messages are left unsummarized.
+    fn needs_summary(&self, threshold: usize) -> bool {
+        let backing_off = self.summary_failed.is_some_and(|failed| failed.elapsed() < SUMMARY_BACKOFF);
+        !backing_off && self.sequence.len() - self.summary_count() > threshold
     }
 
-    fn get_user_prompt(&self) -> anyhow::Result<Value> {
+    /// Condenses the oldest `batch` turns into a single summary entry.
+    async fn summarize(&mut self, client: &DeepSeekClient, model: ModelType, batch: usize) -> anyhow::Result<()> {
+        let summaries = self.summary_count();
+        let batch = batch.min(self.sequence.len() - summaries);
+        if batch == 0 {
+            return Ok(());
+        }
+
+        let mut user_ids = HashSet::new();
+        let turns = self.sequence.range(summaries..summaries + batch)
+            .map(|msg| msg.format(&mut user_ids))
+            .collect::<Vec<String>>().join("\n");
+
+        let prompt = format!(r#"
+请将以下聊天记录压缩为一段简短的摘要。
+要求：
+- 保留关键事实、结论和未解决的问题
+- 使用 user_id 指代用户，不要遗漏参与者
+- 使用第三人称客观描述
+- 直接输出摘要，不要任何解释或前缀
+
+聊天记录：
+{}
+        "#, turns);
+
+        metrics::inc(&METRICS.completion_calls);
+        let resp = CompletionsRequestBuilder::new(&vec![
+            MessageRequest::User(UserMessageRequest { content: prompt, name: None })
+        ]).use_model(model).do_request(client).await?.must_response();
+
+        let summary = resp.choices.first()
+            .and_then(|choice| choice.message.as_ref())
+            .map(|msg| msg.content.trim().to_string())
+            .filter(|content| !content.is_empty())
+            .ok_or_else(|| anyhow::anyhow!("Empty summary"))?;
+
+        self.summary_failed = None;
+        self.sequence.drain(summaries..summaries + batch);
+        self.sequence.insert(summaries, ChatMsg::summary(summary));
+        if summaries + 1 > MAX_SUMMARIES {
+            self.sequence.pop_front();
+        }
+
+        Ok(())
+    }
+
+    /// Earlier messages older than `ttl` are left out.
+    /// In groups, the model may answer [NO_RESPONSE] to stay silent.
+    fn get_user_prompt(&self, private: bool, ttl: Duration) -> anyhow::Result<Value> {
         let mut lines = Vec::new();
         let mut user_ids = HashSet::new();
     
         lines.push("最近的历史消息（按时间顺序，最新在最后）：".to_string());
-        for msg in &self.sequence {
-            if msg.time_valid(Duration::from_secs(1300)) {
+        // The latest message is listed on its own below.
+        let earlier = self.sequence.len().saturating_sub(1);
+        for msg in self.sequence.iter().take(earlier) {
+            if msg.time_valid(ttl) {
                 lines.push(msg.format(&mut user_ids));
             }
         }
-        lines.pop();
         lines.push("".to_string());
         if let Some(latest) = self.sequence.back() {
             lines.push("你需要回复最新消息：".to_string());
@@ -315,10 +957,13 @@ impl ChannelHistory {
 
         lines.push("".to_string());
         lines.push("你是群聊机器人。".to_string());
-        // lines.push("请根据背景信息，判断是否需要回复。".to_string());
-        // lines.push("如果不需要，请输出 NO_RESPONSE。".to_string());
-        // lines.push("若需要，直接给出发送到群里的回复内容。".to_string());
-        lines.push("直接给出发送到群里的回复内容。".to_string());
+        if private {
+            lines.push("直接给出发送到群里的回复内容。".to_string());
+        } else {
+            lines.push("请根据背景信息，判断是否需要回复。".to_string());
+            lines.push(format!("如果不需要，请只输出 {}。", NO_RESPONSE));
+            lines.push("若需要，直接给出发送到群里的回复内容。".to_string());
+        }
 
         Ok(json!({
             "role": "user",
@@ -331,16 +976,21 @@ pub enum ChatMsg {
     User {
         user: User,
         content: String,
-        timestamp: Instant
+        timestamp: DateTime<Utc>
     },
     Assistant {
         content: String,
-        timestamp: Instant
+        timestamp: DateTime<Utc>
     },
     Tool {
         name: String,
         content: String,
-        timestamp: Instant
+        timestamp: DateTime<Utc>
+    },
+    /// Condensed older turns. Never summarized again.
+    Summary {
+        content: String,
+        timestamp: DateTime<Utc>
     }
 }
 
@@ -362,28 +1012,398 @@ impl ChatMsg {
             ChatMsg::Tool { name, content, timestamp: _ } => format!(
                 "[Tool:{}] {}",
                 name, content
-            )
+            ),
+            ChatMsg::Summary { content, timestamp: _ } => format!("[更早的聊天摘要] {}", content)
         }
     }
 
-    fn assistant(content: String) -> Self {
-        ChatMsg::Assistant { content, timestamp: Instant::now() }
+    fn assistant(content: String, timestamp: DateTime<Utc>) -> Self {
+        ChatMsg::Assistant { content, timestamp }
     }
 
-    fn user(user: User, content: String) -> Self {
-        ChatMsg::User { user, content, timestamp: Instant::now() }
+    fn user(user: User, content: String, timestamp: DateTime<Utc>) -> Self {
+        ChatMsg::User { user, content, timestamp }
     }
 
     fn tool(name: String, content: String) -> Self {
-        ChatMsg::Tool { name, content, timestamp: Instant::now() }
+        ChatMsg::Tool { name, content, timestamp: Utc::now() }
+    }
+
+    fn summary(content: String) -> Self {
+        ChatMsg::Summary { content, timestamp: Utc::now() }
     }
 
+    /// Whether it was sent within `dura`. Times in the future, from clocks
+    /// out of sync, count as valid.
     fn time_valid(&self, dura: Duration) -> bool {
-        let now = Instant::now();
-        match self {
-            ChatMsg::Assistant { content: _, timestamp } => now - *timestamp <= dura,
-            ChatMsg::User { user: _, content:_ , timestamp } => now - *timestamp <= dura,
-            ChatMsg::Tool { name: _, content:_ , timestamp } => now - *timestamp <= dura
+        let timestamp = match self {
+            ChatMsg::Assistant { content: _, timestamp } => timestamp,
+            ChatMsg::User { user: _, content:_ , timestamp } => timestamp,
+            ChatMsg::Tool { name: _, content:_ , timestamp } => timestamp,
+            ChatMsg::Summary { content: _, timestamp } => timestamp
+        };
+        (Utc::now() - *timestamp).to_std().ok().is_none_or(|age| age <= dura)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{SELFID, adapters::{API, mock::MockPoster}, config::{Config, Trigger}, logging::LoggerProvider};
+
+    const HISTORY_LEN: usize = 20;
+    const HISTORY_TTL: Duration = Duration::from_secs(3600);
+
+    fn group_message(user_id: usize, content: &str) -> Message {
+        Message::builder()
+            .sender(user_id)
+            .nickname(&format!("User{}", user_id))
+            .group(1)
+            .text(content)
+            .build()
+    }
+
+    /// Requests completions from `server`.
+    fn mock_chat(server: &mockito::Server) -> ChatApi {
+        ChatApi {
+            http: reqwest::Client::new(),
+            endpoint: format!("{}/chat/completions", server.url()),
+            key: "test".to_string()
         }
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_is_retryable() -> anyhow::Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        for (status, retryable) in [(503, true), (429, true), (401, false), (400, false)] {
+            let _mock = server.mock("GET", format!("/{}", status).as_str()).with_status(status).create_async().await;
+            let err = reqwest::get(format!("{}/{}", server.url(), status)).await?.error_for_status().unwrap_err();
+            assert_eq!(is_retryable(&err.into()), retryable, "状态码 {} 的判断有误", status);
+        }
+        assert!(!is_retryable(&anyhow::anyhow!("max 512 tokens, status 503")), "不应按文本中的数字匹配");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_with_retry_gives_up() -> anyhow::Result<()> {
+        let _logger_thread = LoggerProvider::init_with(|| Arc::new(Config::default()));
+        let mut server = mockito::Server::new_async().await;
+        let mock = server.mock("GET", "/")
+            .with_status(502)
+            .expect(3)
+            .create_async().await;
+
+        let url = &server.url();
+        let result = with_retry(2, Duration::ZERO, || async move {
+            Ok(reqwest::get(url).await?.error_for_status()?)
+        }).await;
+
+        assert!(result.is_err());
+        mock.assert_async().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_retried_before_sending() -> anyhow::Result<()> {
+        let _logger_thread = LoggerProvider::init_with(|| Arc::new(Config::default()));
+        let (poster, requests) = MockPoster::install();
+        let (tools, message) = (ToolRegistry::new(), group_message(1, "hello"));
+
+        let mut server = mockito::Server::new_async().await;
+        let failing = server.mock("POST", "/chat/completions")
+            .with_status(503)
+            .expect(1)
+            .create_async().await;
+        let answering = server.mock("POST", "/chat/completions")
+            .with_body(format!("data: {}\n\ndata: [DONE]\n\n", json!({ "choices": [{ "delta": { "content": "你好。" } }] })))
+            .expect(1)
+            .create_async().await;
+
+        let chat = mock_chat(&server);
+        let config = ThinkerConfig { stream: true, retry_backoff_ms: 0, ..Default::default() };
+        let replier = Replier { chat: &chat, config: &config, tools: &tools, poster: &poster, message: &message };
+        replier.reply("deepseek-chat", Vec::new(), &mut ChannelHistory::new()).await?;
+
+        failing.assert_async().await;
+        answering.assert_async().await;
+        assert!(matches!(
+            requests.lock().unwrap().last(),
+            Some(API::SendGroupText { content, .. }) if content == "你好。"
+        ), "流式请求失败后应重试");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_called_uses_config() {
+        SELFID.lock().unwrap().replace(0);
+
+        let config = ThinkerConfig {
+            triggers: vec![Trigger { keyword: "Bot".to_string(), score: 30 }],
+            threshold: 50,
+            at_bonus: 100,
+            buff_bonus: 30,
+            ..Default::default()
+        };
+
+        assert!(!Thinker::get_called(&group_message(1, "hello bot"), false, &config, None));
+        assert!(Thinker::get_called(&group_message(1, "hello bot"), true, &config, None));
+        assert!(!Thinker::get_called(&group_message(1, "rustaris?"), true, &config, None));
+    }
+
+    #[test]
+    fn test_get_called_uses_group_override() {
+        SELFID.lock().unwrap().replace(0);
+
+        let config = ThinkerConfig {
+            triggers: vec![Trigger { keyword: "bot".to_string(), score: 30 }],
+            threshold: 50,
+            ..Default::default()
+        };
+        let lenient = GroupOverride { threshold: Some(30), ..Default::default() };
+        let disabled = GroupOverride { enabled: Some(false), ..Default::default() };
+
+        assert!(Thinker::get_called(&group_message(1, "hello bot"), false, &config, Some(&lenient)), "应使用群的阈值");
+        assert!(!Thinker::get_called(&group_message(1, "bot bot"), true, &config, Some(&disabled)), "禁用的群不应回复");
+    }
+
+    #[test]
+    fn test_get_called_uses_profile() {
+        SELFID.lock().unwrap().replace(0);
+
+        let mut config = ThinkerConfig {
+            triggers: vec![Trigger { keyword: "bot".to_string(), score: 50 }],
+            threshold: 50,
+            ..Default::default()
+        };
+        config.profiles.insert("work".to_string(), PromptProfile {
+            triggers: Some(vec![Trigger { keyword: "助手".to_string(), score: 50 }]),
+            ..Default::default()
+        });
+        let work = GroupOverride { profile: Some("work".to_string()), ..Default::default() };
+        let missing = GroupOverride { profile: Some("casual".to_string()), ..Default::default() };
+
+        assert!(Thinker::get_called(&group_message(1, "助手在吗"), false, &config, Some(&work)), "应使用人设的触发词");
+        assert!(!Thinker::get_called(&group_message(1, "hello bot"), false, &config, Some(&work)), "人设的触发词应替换全局的");
+        assert!(Thinker::get_called(&group_message(1, "hello bot"), false, &config, Some(&missing)), "不存在的人设应回退到全局配置");
+    }
+
+    #[test]
+    fn test_caption_queues_keep_order() {
+        let mut queues = CaptionQueues::default();
+        let first = queues.captioning(&group_message(1, "图一"));
+        let second = queues.captioning(&group_message(1, "图二"));
+        assert!(queues.hold(group_message(2, "文字")).is_none(), "图片之后的消息应等待");
+        let elsewhere = Message::builder().sender(3).group(2).text("别的群").build();
+        assert!(queues.hold(elsewhere).is_some(), "其他频道的消息不应等待");
+
+        assert!(queues.captioned(second, group_message(1, "图二")).is_empty(), "前面的图片未完成时不应处理");
+        let ready = queues.captioned(first, group_message(1, "图一"));
+        assert_eq!(ready.iter().map(|msg| msg.raw.as_str()).collect::<Vec<&str>>(), vec!["图一", "图二", "文字"], "应按收到的顺序处理");
+        assert!(queues.is_empty());
+    }
+
+    #[test]
+    fn test_channel_cooldown() {
+        let mut history = ChannelHistory::new();
+        assert!(!history.cooling_down(Duration::from_secs(10)), "未回复过的频道不应处于冷却中");
+
+        history.last_reply = Some(Instant::now());
+        assert!(history.cooling_down(Duration::from_secs(10)), "刚回复过的频道应处于冷却中");
+        assert!(!history.cooling_down(Duration::ZERO), "冷却时间为 0 时不应冷却");
+    }
+
+    #[test]
+    fn test_split_reply() {
+        assert_eq!(split_reply("短消息。", 1500), vec!["短消息。"]);
+
+        let parts = split_reply("第一句。第二句！\n第三段", 15);
+        assert_eq!(parts, vec!["第一句。", "第二句！", "第三段"]);
+
+        let long = "啊".repeat(10);
+        let parts = split_reply(&long, 9);
+        assert!(parts.iter().all(|part| part.len() <= 9), "分段超出了字节上限");
+        assert_eq!(parts.concat(), long, "分段后内容不应丢失");
+    }
+
+    #[tokio::test]
+    async fn test_empty_reply_not_sent() -> anyhow::Result<()> {
+        let _logger_thread = LoggerProvider::init_with(|| Arc::new(Config::default()));
+        let (poster, requests) = MockPoster::install();
+        let (tools, message) = (ToolRegistry::new(), group_message(1, "hello"));
+
+        // 第一轮只有空白内容和工具调用，拿到工具结果后才回复
+        let call = json!({ "id": "call_0", "type": "function", "function": { "name": "search_memory", "arguments": "{}" } });
+        let mut server = mockito::Server::new_async().await;
+        let calling = server.mock("POST", "/chat/completions")
+            .with_body(json!({ "choices": [{ "message": { "role": "assistant", "content": "  ", "tool_calls": [call] } }] }).to_string())
+            .expect(1)
+            .create_async().await;
+        let answering = server.mock("POST", "/chat/completions")
+            .match_body(mockito::Matcher::Regex("tool_call_id".to_string()))
+            .with_body(json!({ "choices": [{ "message": { "role": "assistant", "content": "你好。" } }] }).to_string())
+            .expect(1)
+            .create_async().await;
+
+        let chat = mock_chat(&server);
+        let config = ThinkerConfig::default();
+        let replier = Replier { chat: &chat, config: &config, tools: &tools, poster: &poster, message: &message };
+        let mut history = ChannelHistory::new();
+        replier.reply("deepseek-chat", Vec::new(), &mut history).await?;
+
+        calling.assert_async().await;
+        answering.assert_async().await;
+        let sent = requests.lock().unwrap().iter().filter_map(|api| match api {
+            API::SendGroupText { content, .. } => Some(content.clone()),
+            _ => None
+        }).collect::<Vec<String>>();
+        assert_eq!(sent, vec!["你好。"], "空内容不应触发发送");
+        let replies = history.sequence.iter().filter(|msg| matches!(msg, ChatMsg::Assistant { .. })).count();
+        assert_eq!(replies, 1, "空内容不应被视为已发送");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_sends_markdown() -> anyhow::Result<()> {
+        let _logger_thread = LoggerProvider::init_with(|| Arc::new(Config::default()));
+        let (poster, requests) = MockPoster::install();
+        let (tools, message) = (ToolRegistry::new(), group_message(1, "hello"));
+
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server.mock("POST", "/chat/completions")
+            .with_body(format!("data: {}\n\ndata: [DONE]\n\n", json!({ "choices": [{ "delta": { "content": "**你好**" } }] })))
+            .create_async().await;
+
+        let chat = mock_chat(&server);
+        let config = ThinkerConfig { stream: true, markdown: true, ..Default::default() };
+        let replier = Replier { chat: &chat, config: &config, tools: &tools, poster: &poster, message: &message };
+        replier.reply("deepseek-chat", Vec::new(), &mut ChannelHistory::new()).await?;
+
+        assert!(matches!(
+            requests.lock().unwrap().last(),
+            Some(API::SendGroupMsg { content, .. }) if matches!(content.as_slice(), [MessageArrayItem::Markdown(text)] if text == "**你好**")
+        ), "流式回复也应按 markdown 发送");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tool_loop_gives_up() -> anyhow::Result<()> {
+        let _logger_thread = LoggerProvider::init_with(|| Arc::new(Config::default()));
+        let (poster, requests) = MockPoster::install();
+        let (tools, message) = (ToolRegistry::new(), group_message(1, "帮我算一下"));
+
+        // 模型每轮都只要求调用工具，循环只能靠轮数上限结束
+        let call = json!({ "id": "call_1", "type": "function", "function": { "name": "no_such_tool", "arguments": "{}" } });
+        let mut delta = call.clone();
+        delta["index"] = json!(0);
+        let streamed = format!("data: {}\n\ndata: [DONE]\n\n", json!({ "choices": [{ "delta": { "tool_calls": [delta] } }] }));
+        let whole = json!({ "choices": [{ "message": { "role": "assistant", "content": "", "tool_calls": [call] } }] }).to_string();
+
+        for (stream, body) in [(true, streamed), (false, whole)] {
+            let mut server = mockito::Server::new_async().await;
+            let mock = server.mock("POST", "/chat/completions")
+                .with_body(body)
+                .expect(3)
+                .create_async().await;
+
+            let chat = mock_chat(&server);
+            let config = ThinkerConfig { max_tool_rounds: 3, stream, ..Default::default() };
+            let replier = Replier { chat: &chat, config: &config, tools: &tools, poster: &poster, message: &message };
+            replier.reply("deepseek-chat", Vec::new(), &mut ChannelHistory::new()).await?;
+
+            mock.assert_async().await;
+            assert!(matches!(
+                requests.lock().unwrap().last(),
+                Some(API::SendGroupText { group_id: 1, content }) if content == TOOL_LOOP_FALLBACK
+            ), "超过轮数上限后应发送兜底回复（stream: {}）", stream);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_reply() {
+        let plain = build_reply(5, "你好", false, false);
+        assert!(matches!(plain.as_slice(), [MessageArrayItem::Text(text)] if text == "你好"));
+
+        let mentioned = build_reply(5, "你好", true, true);
+        assert!(matches!(mentioned.first(), Some(MessageArrayItem::At(5))), "应先 @ 发送者");
+        assert!(matches!(mentioned.last(), Some(MessageArrayItem::Markdown(text)) if text == "你好"));
+    }
+
+    #[test]
+    fn test_old_messages_left_out() -> anyhow::Result<()> {
+        SELFID.lock().unwrap().replace(0);
+
+        let ttl = chrono::TimeDelta::from_std(HISTORY_TTL)?;
+        let minute = chrono::TimeDelta::minutes(1);
+        let sent_at = |text: &str, age: chrono::TimeDelta| Message::builder().sender(1).group(1).text(text).time(Utc::now() - age).build();
+
+        let mut history = ChannelHistory::new();
+        history.insert_msg(&sent_at("窗口之外", ttl + minute), HISTORY_LEN, |_| None);
+        history.insert_msg(&sent_at("窗口之内", ttl - minute), HISTORY_LEN, |_| None);
+        history.insert_msg(&group_message(3, "最新"), HISTORY_LEN, |_| None);
+
+        let prompt = history.get_user_prompt(false, HISTORY_TTL)?;
+        let content = prompt["content"].as_str().unwrap_or_default();
+        assert!(!content.contains("窗口之外"), "应按发送时间排除过早的消息");
+        assert!(content.contains("窗口之内"), "窗口内的消息应保留");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_summary_backs_off_after_failure() {
+        SELFID.lock().unwrap().replace(0);
+
+        let threshold = 10;
+        let mut history = ChannelHistory::new();
+        for i in 0..=threshold {
+            history.insert_msg(&group_message(1, &i.to_string()), HISTORY_LEN, |_| None);
+        }
+        assert!(history.needs_summary(threshold), "超过阈值后应需要摘要");
+
+        history.summary_failed = Some(Instant::now());
+        assert!(!history.needs_summary(threshold), "摘要失败后应暂缓重试");
+    }
+
+    #[test]
+    fn test_no_response_only_in_groups() -> anyhow::Result<()> {
+        SELFID.lock().unwrap().replace(0);
+
+        let mut history = ChannelHistory::new();
+        history.insert_msg(&group_message(1, "你好"), HISTORY_LEN, |_| None);
+
+        let group = history.get_user_prompt(false, HISTORY_TTL)?;
+        assert!(group["content"].as_str().unwrap_or_default().contains(NO_RESPONSE), "群聊中应允许不回复");
+        let private = history.get_user_prompt(true, HISTORY_TTL)?;
+        assert!(!private["content"].as_str().unwrap_or_default().contains(NO_RESPONSE), "私聊中应总是回复");
+
+        assert!(is_silent(" NO_RESPONSE\n"));
+        assert!(!is_silent("NO_RESPONSE 之外还有话"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_user_prompt_keeps_all_messages() -> anyhow::Result<()> {
+        SELFID.lock().unwrap().replace(0);
+
+        let mut history = ChannelHistory::new();
+        for (user_id, content) in [(1, "第一条"), (2, "第二条"), (3, "第三条")] {
+            history.insert_msg(&group_message(user_id, content), HISTORY_LEN, |_| None);
+        }
+
+        let prompt = history.get_user_prompt(false, HISTORY_TTL)?;
+        let content = prompt["content"].as_str().unwrap_or_default();
+        for expected in ["第一条", "第二条", "第三条"] {
+            assert!(content.contains(expected), "prompt 中缺少消息：{}", expected);
+        }
+
+        Ok(())
+    }
+}
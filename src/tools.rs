@@ -1,10 +1,14 @@
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::Duration};
 
+use chrono::{DateTime, Datelike, Local, NaiveDateTime, TimeDelta, TimeZone, Weekday};
+use chrono_tz::Tz;
+use rand::{Rng, SeedableRng, rngs::StdRng};
 use rust_mc_status::{McClient, ServerEdition};
 use serde_json::{Value, json};
 
 use async_trait::async_trait;
-use crate::{get_logger, get_poster, memory::{MemoryService, Scope}, objects::{Message, MessageArrayItem}};
+use futures::future::join_all;
+use crate::{get_config, get_logger, get_poster, memory::{AliasesMapping, MemoryError, MemoryService, Scope, recall_scopes}, metrics::METRICS, objects::{Message, MessageArrayItem}, reminders::{self, Reminder, Reminders}};
 
 
 
@@ -14,25 +18,125 @@ pub trait Tool: Send + Sync {
     fn description(&self) -> &str;
     async fn call(&self, args: Value, msg: &Message) -> anyhow::Result<Value>;
     fn parameters_schema(&self) -> Value;
+    /// Whether arguments are checked against [Tool::parameters_schema] before [Tool::call].
+    fn validate_args(&self) -> bool { true }
+    /// Whether the tool may run alongside other calls from the same response.
+    /// Tools changing shared state, like memories, should return `false`.
+    fn parallel_safe(&self) -> bool { true }
+    /// Whether the tool neither changes anything nor sends messages itself.
+    /// Only these run in dry-run mode, see `thinker.dry_run`.
+    fn read_only(&self) -> bool { self.parallel_safe() }
+}
+
+/// How often a tool has been called.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct ToolStats {
+    pub calls: usize,
+    /// Calls that returned an error or had invalid arguments.
+    pub failures: usize
+}
+
+/// Usage counters of the tools in a [ToolRegistry], shareable with other tasks.
+#[derive(Default)]
+pub struct ToolMetrics {
+    counters: Mutex<HashMap<String, ToolStats>>
+}
+
+impl ToolMetrics {
+    fn record(&self, name: &str, failed: bool) {
+        let mut counters = self.counters.lock().unwrap();
+        let stats = counters.entry(name.to_string()).or_default();
+        stats.calls += 1;
+        if failed { stats.failures += 1; }
+    }
+
+    /// Counters of every tool called so far, sorted by name.
+    pub fn snapshot(&self) -> Vec<(String, ToolStats)> {
+        let mut stats = self.counters.lock().unwrap().iter()
+            .map(|(name, stats)| (name.clone(), *stats))
+            .collect::<Vec<(String, ToolStats)>>();
+        stats.sort_by(|a, b| a.0.cmp(&b.0));
+        stats
+    }
 }
 
 pub struct ToolRegistry {
-    tools: HashMap<String, Arc<dyn Tool>>
+    tools: HashMap<String, Arc<dyn Tool>>,
+    /// Compiled schemas of the tools that validate their arguments.
+    validators: HashMap<String, Arc<jsonschema::Validator>>,
+    metrics: Arc<ToolMetrics>
 }
 
 impl ToolRegistry {
     pub fn new() -> Self {
-        Self { tools: HashMap::new() }
+        Self { tools: HashMap::new(), validators: HashMap::new(), metrics: Arc::new(ToolMetrics::default()) }
+    }
+
+    pub fn metrics(&self) -> Arc<ToolMetrics> {
+        self.metrics.clone()
+    }
+
+    /// Call and failure counts of every tool called so far.
+    pub fn stats(&self) -> Vec<(String, ToolStats)> {
+        self.metrics.snapshot()
     }
 
     pub fn register<T: Tool + 'static>(&mut self, tool: T) {
-        self.tools.insert(tool.name().to_string(), Arc::new(tool));
+        let name = tool.name().to_string();
+        self.validators.remove(&name);
+        if tool.validate_args() {
+            match jsonschema::validator_for(&tool.parameters_schema()) {
+                Ok(validator) => { self.validators.insert(name.clone(), Arc::new(validator)); },
+                Err(err) => get_logger().warn(&format!("Invalid schema of tool {}, arguments won't be validated: {}", name, err))
+            }
+        }
+        self.tools.insert(name, Arc::new(tool));
     }
 
     pub fn get(&self, name: &str) -> Option<Arc<dyn Tool>> {
         self.tools.get(name).cloned()
     }
 
+    /// Names of the registered tools, sorted.
+    pub fn names(&self) -> Vec<String> {
+        let mut names = self.tools.keys().cloned().collect::<Vec<String>>();
+        names.sort();
+        names
+    }
+
+    /// `(name, description)` of the registered tools, sorted by name.
+    pub fn describe(&self) -> Vec<(String, String)> {
+        self.names().into_iter().filter_map(|name| {
+            let description = self.tools.get(&name)?.description().to_string();
+            Some((name, description))
+        }).collect()
+    }
+
+    /// Runs the calls given as `(name, id, args)` and returns their results in
+    /// the same order. Calls to tools that aren't [Tool::parallel_safe] run one
+    /// by one after the others.
+    pub async fn execute_all(&self, calls: &[(&str, &str, &str)], msg: &Message) -> Vec<Value> {
+        let (parallel, serial): (Vec<usize>, Vec<usize>) = (0..calls.len())
+            .partition(|&i| self.get(calls[i].0).is_none_or(|tool| tool.parallel_safe()));
+
+        let mut results = vec![Value::Null; calls.len()];
+
+        let outputs = join_all(parallel.iter().map(|&i| {
+            let (name, id, args) = calls[i];
+            self.execute_str_with_err(name, id, args, msg)
+        })).await;
+        for (i, output) in parallel.into_iter().zip(outputs) {
+            results[i] = output;
+        }
+
+        for i in serial {
+            let (name, id, args) = calls[i];
+            results[i] = self.execute_str_with_err(name, id, args, msg).await;
+        }
+
+        results
+    }
+
     pub async fn execute_str_with_err(
         &self,
         name: &str,
@@ -40,14 +144,8 @@ impl ToolRegistry {
         args: &str,
         msg: &Message
     ) -> Value {
-        match self.execute_str(name, id, args, msg).await {
-            Ok(result) => result,
-            Err(err) => json!({
-                "role": "tool",
-                "tool_call_id": id,
-                "content": format!("工具 '{}' 调用失败：{}", name, err)
-            })
-        }
+        self.execute_str(name, id, args, msg).await
+            .unwrap_or_else(|err| err.to_message(name, id))
     }
 
     pub async fn execute_str(
@@ -56,13 +154,12 @@ impl ToolRegistry {
         id: &str,
         args: &str,
         msg: &Message
-    ) -> anyhow::Result<Value> {
-        self.execute(
-            name,
-            id,
-            serde_json::from_str(args)
-            .map_err(|err| anyhow::anyhow!("Invalid JSON args: {}", err))?,
-            msg).await
+    ) -> Result<Value, ToolError> {
+        let args = serde_json::from_str(args).map_err(|err| {
+            self.metrics.record(name, true);
+            ToolError::Invalid(format!("Invalid JSON args: {}", err))
+        })?;
+        self.execute(name, id, args, msg).await
     }
 
     pub async fn execute_with_err(
@@ -72,14 +169,8 @@ impl ToolRegistry {
         args: Value,
         msg: &Message
     ) -> Value {
-        match self.execute(name, id, args, msg).await {
-            Ok(result) => result,
-            Err(err) => json!({
-                "role": "tool",
-                "tool_call_id": id,
-                "content": format!("工具 '{}' 调用失败：{}", name, err)
-            })
-        }
+        self.execute(name, id, args, msg).await
+            .unwrap_or_else(|err| err.to_message(name, id))
     }
 
     pub async fn execute(
@@ -88,14 +179,44 @@ impl ToolRegistry {
         id: &str,
         args: Value,
         msg: &Message
-    ) -> anyhow::Result<Value> {
+    ) -> Result<Value, ToolError> {
         let tool = 
-            self.get(name).ok_or_else(|| anyhow::anyhow!("Tool not found: {}", name))?; 
+            self.get(name).ok_or_else(|| ToolError::Invalid(format!("Tool not found: {}", name)))?; 
+
+        if let Some(validator) = self.validators.get(name) {
+            let errors = validator.iter_errors(&args).map(|err| {
+                let path = err.instance_path.to_string();
+                format!("- {}：{}", if path.is_empty() { "/" } else { &path }, err)
+            }).collect::<Vec<String>>();
+            if !errors.is_empty() {
+                self.metrics.record(name, true);
+                get_logger().debug(&format!("Invalid arguments for {}: {}", name, args));
+                return Ok(json!({
+                    "role": "tool",
+                    "tool_call_id": id,
+                    "content": format!("工具 '{}' 参数无效：\n{}", name, errors.join("\n"))
+                }));
+            }
+        }
+
+        if get_config().thinker.dry_run && !tool.read_only() {
+            get_logger().chat(&format!("[dry run] Skipped {}: {}", name, args));
+            return Ok(json!({
+                "role": "tool",
+                "tool_call_id": id,
+                "content": format!("（试运行）工具 '{}' 未执行，当作已成功", name)
+            }));
+        }
+
         get_logger().debug(&format!("Calling: {}", tool.name()));
+        METRICS.tool_call(name);
+        let result = tool.call(args, msg).await;
+        self.metrics.record(name, result.is_err());
+        let content = truncate_result(result?, get_config().tools.max_result_chars);
         Ok(json!({
             "role": "tool",
             "tool_call_id": id,
-            "content": tool.call(args, msg).await?
+            "content": content
         }))
     }
     
@@ -113,6 +234,72 @@ impl ToolRegistry {
     }
 }
 
+/// Why a tool call failed, deciding what the model is told and how loudly it's logged.
+#[derive(Debug)]
+pub enum ToolError {
+    /// The call itself was wrong, e.g. an unknown tool or a missing memory.
+    Invalid(String),
+    /// A service the tool relies on failed, trying again later may work.
+    Transient(String),
+    Failed(String)
+}
+
+impl From<anyhow::Error> for ToolError {
+    fn from(err: anyhow::Error) -> Self {
+        if let Some(err) = err.downcast_ref::<MemoryError>() {
+            return match err {
+                MemoryError::NotFound(_) | MemoryError::Invalid(_) => ToolError::Invalid(err.to_string()),
+                MemoryError::Db(_) | MemoryError::Embed(_) => ToolError::Transient(err.to_string())
+            };
+        }
+        if err.downcast_ref::<reqwest::Error>().is_some() {
+            return ToolError::Transient(err.to_string());
+        }
+        ToolError::Failed(err.to_string())
+    }
+}
+
+impl ToolError {
+    /// The tool message answering call `id` of tool `name`. The error is logged as well.
+    pub fn to_message(&self, name: &str, id: &str) -> Value {
+        let content = match self {
+            ToolError::Invalid(err) => {
+                get_logger().debug(&format!("Invalid call to {}: {}", name, err));
+                format!("工具 '{}' 调用有误：{}", name, err)
+            }
+            ToolError::Transient(err) => {
+                get_logger().warn(&format!("Tool {} is unavailable: {}", name, err));
+                format!("工具 '{}' 暂时不可用：{}，可以稍后重试", name, err)
+            }
+            ToolError::Failed(err) => {
+                get_logger().error(&format!("Tool {} failed: {}", name, err));
+                format!("工具 '{}' 调用失败：{}", name, err)
+            }
+        };
+        json!({
+            "role": "tool",
+            "tool_call_id": id,
+            "content": content
+        })
+    }
+}
+
+/// Cuts a tool result longer than `max_chars`, so that one verbose result
+/// doesn't take up the whole context.
+fn truncate_result(content: Value, max_chars: usize) -> Value {
+    let text = match &content {
+        Value::String(text) => text.clone(),
+        other => other.to_string()
+    };
+    let len = text.chars().count();
+    if len <= max_chars {
+        return content;
+    }
+
+    let kept = text.chars().take(max_chars).collect::<String>();
+    Value::String(format!("{}…（结果过长，已省略 {} 字）", kept, len - max_chars))
+}
+
 macro_rules! extract {
     ($json:expr, $key:literal, $extractor:ident) => {
         $json.get($key)
@@ -199,7 +386,7 @@ impl NeteaseMusicTool {
             client: reqwest::ClientBuilder::new()
                 .timeout(Duration::from_secs(10))
                 .build()?,
-            api_root: std::env::var("NETEASE_API_ROOT").unwrap_or("http://192.168.3.38:8099".to_string())
+            api_root: get_config().api.netease_root.clone()
         })
     }
 }
@@ -214,6 +401,11 @@ impl Tool for NeteaseMusicTool {
         "解析网易云音乐的歌曲并将对应信息转发到群中"
     }
 
+    /// Sends the song and its cover itself.
+    fn read_only(&self) -> bool {
+        false
+    }
+
     fn parameters_schema(&self) -> Value {
         json!({
             "type": "object",
@@ -279,13 +471,13 @@ impl Tool for NeteaseMusicTool {
             if msg.private {
                 match get_poster().upload_private_file(msg.sender.user_id, &url, &file_name).await {
                     Ok(_id) => format!("发送 {} 成功", file_name),
-                    Err(err) => format!("发送 {} 失败: {}", file_name, err.to_string())
+                    Err(err) => format!("发送 {} 失败: {}", file_name, err)
                 }
             } else {
                 if let Some(group) = &msg.group {
                     match get_poster().upload_group_file(group.group_id, &url, &file_name).await {
                         Ok(_id) => format!("发送 {} 成功", file_name),
-                        Err(err) => format!("发送 {} 失败: {}", file_name, err.to_string())
+                        Err(err) => format!("发送 {} 失败: {}", file_name, err)
                     }
                 } else { "Missing group".to_string() }
             }
@@ -312,7 +504,7 @@ impl SearchNeteaseMusicTool {
             client: reqwest::ClientBuilder::new()
                 .timeout(Duration::from_secs(10))
                 .build()?,
-            api_root: std::env::var("NETEASE_API_ROOT").unwrap_or("http://192.168.3.38:8099".to_string())
+            api_root: get_config().api.netease_root.clone()
         })
     }
 }
@@ -376,6 +568,185 @@ impl Tool for SearchNeteaseMusicTool {
     }
 }
 
+pub struct BilibiliTool {
+    client: reqwest::Client,
+    api_root: String
+}
+
+impl BilibiliTool {
+    pub fn new() -> anyhow::Result<Self> {
+        Ok(Self {
+            client: reqwest::ClientBuilder::new()
+                .timeout(Duration::from_secs(10))
+                .user_agent("Mozilla/5.0")
+                .build()?,
+            api_root: get_config().api.bilibili_root.clone()
+        })
+    }
+
+    /// Finds a BV id like `BV1GJ411x7h7` in `text`.
+    pub fn find_bvid(text: &str) -> Option<String> {
+        text.match_indices("BV").find_map(|(pos, _)| {
+            let id = text[pos..].chars().take_while(char::is_ascii_alphanumeric).collect::<String>();
+            (id.len() == 12).then_some(id)
+        })
+    }
+}
+
+#[async_trait]
+impl Tool for BilibiliTool {
+    fn name(&self) -> &str {
+        "bilibili_video"
+    }
+
+    fn description(&self) -> &str {
+        "解析哔哩哔哩视频的 BV 号或分享链接，返回标题、UP 主、时长等信息"
+    }
+
+    /// Sends the video's cover itself.
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    fn parameters_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "video": {
+                    "type": "string",
+                    "description": "视频的 BV 号或分享链接（包括 b23.tv 短链接）"
+                },
+                "send_cover": {
+                    "type": "boolean",
+                    "default": false,
+                    "description": "是否将视频封面发送到聊天中"
+                }
+            },
+            "required": ["video"]
+        })
+    }
+
+    async fn call(&self, args: Value, msg: &Message) -> anyhow::Result<Value> {
+        let video = extract!(args, "video", as_str);
+        let send_cover = extract_optional!(args, "send_cover", as_bool).unwrap_or(false);
+
+        let bvid = match BilibiliTool::find_bvid(&video) {
+            Some(bvid) => bvid,
+            None if video.contains("b23.tv") => {
+                // Short links redirect to the full video url.
+                let resp = self.client.get(video.trim()).send().await?;
+                BilibiliTool::find_bvid(resp.url().as_str())
+                    .ok_or_else(|| anyhow::anyhow!("短链接中没有找到 BV 号"))?
+            }
+            None => return Err(anyhow::anyhow!("没有找到 BV 号：{}", video))
+        };
+
+        let resp = self.client.get(format!("{}/x/web-interface/view?bvid={}", self.api_root, bvid))
+            .send().await?.json::<Value>().await?;
+        if extract!(resp, "code", as_i64) != 0 {
+            let message = extract_optional!(resp, "message", as_str).unwrap_or_default();
+            return Err(anyhow::anyhow!("获取视频信息失败：{}", message));
+        }
+        let data = extract!(resp, "data", as_object);
+
+        let title = extract!(data, "title", as_str);
+        let owner = extract!(extract!(data, "owner", as_object), "name", as_str);
+        let duration = extract!(data, "duration", as_u64);
+        let cover_url = extract!(data, "pic", as_str);
+
+        let mut info = vec![
+            format!("title: {}", title),
+            format!("up: {}", owner),
+            format!("duration: {}:{:02}", duration / 60, duration % 60),
+            format!("cover: {}", cover_url),
+            format!("url: https://www.bilibili.com/video/{}", bvid)
+        ];
+
+        if send_cover {
+            if msg.quick_send_msg(vec![MessageArrayItem::Image { summary: None, file: None, url: cover_url, file_size: None }]).await {
+                info.push("封面发送成功".to_string());
+            } else {
+                info.push("封面发送失败".to_string());
+            }
+        }
+
+        Ok(Value::String(info.join("\n")))
+    }
+}
+
+/// Longest snippet of a web search result, in chars.
+const SNIPPET_CHARS: usize = 200;
+
+pub struct WebSearchTool {
+    client: reqwest::Client,
+    api_root: String,
+    limit: usize
+}
+
+impl WebSearchTool {
+    pub fn new(limit: usize) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: reqwest::ClientBuilder::new()
+                .timeout(Duration::from_secs(10))
+                .build()?,
+            api_root: get_config().api.search_root()?,
+            limit: limit.clamp(1, 10)
+        })
+    }
+}
+
+#[async_trait]
+impl Tool for WebSearchTool {
+    fn name(&self) -> &str {
+        "web_search"
+    }
+
+    fn description(&self) -> &str {
+        "在互联网上搜索，返回相关网页的标题、摘要和链接。用于回答时事或需要最新信息的问题"
+    }
+
+    fn parameters_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "query": {
+                    "type": "string",
+                    "description": "搜索关键词"
+                }
+            },
+            "required": ["query"]
+        })
+    }
+
+    async fn call(&self, args: Value, _msg: &Message) -> anyhow::Result<Value> {
+        let query = extract!(args, "query", as_str);
+
+        let url = reqwest::Url::parse_with_params(
+            &format!("{}/search", self.api_root.trim_end_matches('/')),
+            &[("q", query.as_str()), ("format", "json")]
+        )?;
+        let resp = self.client.get(url).send().await?.json::<Value>().await?;
+        let array = extract!(resp, "results", as_array);
+
+        let mut result = Vec::<String>::new();
+
+        result.push(format!("找到 {} 个结果（最多 {} 个结果）：", array.len().min(self.limit), self.limit));
+
+        for item in array.iter().take(self.limit) {
+            let title = extract!(item, "title", as_str);
+            let url = extract!(item, "url", as_str);
+            let snippet = extract_optional!(item, "content", as_str).unwrap_or_default();
+            let snippet = if snippet.chars().count() > SNIPPET_CHARS {
+                snippet.chars().take(SNIPPET_CHARS).collect::<String>() + "…"
+            } else { snippet };
+
+            result.push(format!("title: {}\nsnippet: {}\nurl: {}", title, snippet, url));
+        }
+
+        Ok(Value::String(result.join("\n\n")))
+    }
+}
+
 pub struct UpdateMemoryTool {
     pub service: Arc<MemoryService>
 }
@@ -390,6 +761,10 @@ impl Tool for UpdateMemoryTool {
         "更新本条记忆"
     }
 
+    fn parallel_safe(&self) -> bool {
+        false
+    }
+
     fn parameters_schema(&self) -> Value {
         json!({
             "type": "object",
@@ -426,23 +801,37 @@ impl Tool for UpdateMemoryTool {
     async fn call(&self, args: Value, _msg: &Message) -> anyhow::Result<Value> {
 
         let memories = extract!(args, "memories", as_array);
-        let length = memories.len();
+        let mut updated = 0;
+        // Ids the model made up or that were deleted meanwhile, reported back instead of failing the rest.
+        let mut missing = Vec::new();
 
         for item in memories {
             let id = extract!(item, "id", as_i64) as i32;
             let content = extract!(item, "content", as_str);
             let confidence = extract!(item, "confidence", as_f64);
-            self.service.merge(id, &content, confidence).await?;
+            let result = match self.service.content(id).await? {
+                None => Err(MemoryError::NotFound(id)),
+                Some(old) if old == content => self.service.merge_confidence(id, confidence).await,
+                Some(_) => self.service.merge(id, &content, confidence).await
+            };
+            match result {
+                Ok(_) => updated += 1,
+                Err(MemoryError::NotFound(_)) => missing.push(id),
+                Err(err) => return Err(err.into())
+            }
         }
 
-        get_logger().info(&format!("更新了 {} 条记忆", length));
+        get_logger().info(&format!("更新了 {} 条记忆", updated));
 
-        Ok(json!({}))
+        Ok(json!({ "updated": updated, "missing": missing }))
     }
 }
 
 pub struct AddMemoryTool {
-    pub service: Arc<MemoryService>
+    pub service: Arc<MemoryService>,
+    /// If set, a memory at least this similar to the new one is reinforced
+    /// instead of adding a duplicate. See [MemoryService::create_deduped].
+    pub dedup_similarity: Option<f64>
 }
 
 
@@ -456,13 +845,23 @@ impl Tool for AddMemoryTool {
         "创建一条新的记忆"
     }
 
+    fn parallel_safe(&self) -> bool {
+        false
+    }
+
     fn parameters_schema(&self) -> Value {
         json!({
             "type": "object",
-            "porperties": {
+            "properties": {
                 "content": {
                     "type": "string",
                     "description": "记忆内容"
+                },
+                "confidence": {
+                    "type": "number",
+                    "description": "本条记忆的初始可信度，不填则为 0.2",
+                    "minimum": 0.0,
+                    "maximum": 1.0
                 }
             },
             "required": ["content"]
@@ -472,7 +871,15 @@ impl Tool for AddMemoryTool {
     async fn call(&self, args: Value, msg: &Message) -> anyhow::Result<Value> {
 
         let content = extract!(args, "content", as_str);
-        self.service.create(Scope::from(msg), &content).await?;
+        let confidence = extract_optional!(args, "confidence", as_f64);
+        match self.dedup_similarity {
+            Some(min_similarity) => {
+                if !self.service.create_deduped(Scope::from(msg), &content, confidence, min_similarity).await? {
+                    get_logger().info(&format!("记忆已存在，提高了置信度: {}", content));
+                }
+            },
+            None => self.service.create(Scope::from(msg), &content, confidence).await?
+        }
 
         Ok(json!({}))
     }
@@ -492,10 +899,14 @@ impl Tool for DeleteMemoryTool {
         "删除本条记忆。慎用！"
     }
 
+    fn parallel_safe(&self) -> bool {
+        false
+    }
+
     fn parameters_schema(&self) -> Value {
         json!({
             "type": "object",
-            "porperties": {
+            "properties": {
                 "memory_ids": {
                     "type": "array",
                     "items": {
@@ -510,17 +921,15 @@ impl Tool for DeleteMemoryTool {
 
     async fn call(&self, args: Value, _msg: &Message) -> anyhow::Result<Value> {
 
-        let ids = extract!(args, "ids", as_array);
-        let length = ids.len();
+        let ids = extract!(args, "memory_ids", as_array).iter()
+            .filter_map(|id| id.as_i64())
+            .map(|id| id as i32)
+            .collect::<Vec<i32>>();
 
-        for id in ids {
-            if let Some(id) = id.as_i64() {
-                self.service.delete(id as i32).await?;
-            }
-        }
+        let deleted = self.service.delete_many(&ids).await?;
 
-        get_logger().info(&format!("更新了 {} 条记忆", length));
-        Ok(json!({}))
+        get_logger().info(&format!("删除了 {} 条记忆", deleted));
+        Ok(json!({ "deleted": deleted }))
     }
 }
 
@@ -554,10 +963,605 @@ impl Tool for SearchMemoryTool {
     async fn call(&self, args: Value, msg: &Message) -> anyhow::Result<Value> {
 
         let keyword = extract!(args, "keyword", as_str);
-        let similars = self.service.similars(Scope::from(msg), &keyword).await?;
+        let user_memories = msg.group.as_ref().is_some_and(|group| self.service.user_memories_in(group.group_id));
+        let similars = self.service.similars_across(&recall_scopes(msg, user_memories), &keyword).await?;
         let result = similars.iter().map(|mem| mem.simplified_plain())
             .collect::<Vec<String>>().join("\n");
 
         Ok(Value::String(result))
     }
-}
\ No newline at end of file
+}
+
+pub struct AddAliasTool {
+    pub map: Arc<Mutex<AliasesMapping>>
+}
+
+#[async_trait]
+impl Tool for AddAliasTool {
+    fn name(&self) -> &str {
+        "add_alias"
+    }
+
+    fn description(&self) -> &str {
+        "记录用户的别称（昵称、外号等）。当聊天中出现某个用户被以其他名字称呼时调用"
+    }
+
+    fn parallel_safe(&self) -> bool {
+        false
+    }
+
+    fn parameters_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "user_id": {
+                    "type": "integer",
+                    "description": "用户id"
+                },
+                "alias": {
+                    "type": "string",
+                    "description": "该用户的别称"
+                }
+            },
+            "required": ["user_id", "alias"]
+        })
+    }
+
+    async fn call(&self, args: Value, _msg: &Message) -> anyhow::Result<Value> {
+
+        let user_id = extract!(args, "user_id", as_u64) as usize;
+        let alias = extract!(args, "alias", as_str);
+
+        if self.map.lock().unwrap().insert(user_id, alias.trim()) {
+            get_logger().info(&format!("记录了 {} 的别称：{}", user_id, alias));
+            Ok(Value::String(format!("已记录 {} 的别称：{}", user_id, alias)))
+        } else {
+            Ok(Value::String(format!("{} 已有别称：{}", user_id, alias)))
+        }
+    }
+}
+
+pub struct RemoveAliasTool {
+    pub map: Arc<Mutex<AliasesMapping>>
+}
+
+#[async_trait]
+impl Tool for RemoveAliasTool {
+    fn name(&self) -> &str {
+        "remove_alias"
+    }
+
+    fn description(&self) -> &str {
+        "删除记错的用户别称。当发现某个别称不属于该用户时调用"
+    }
+
+    fn parallel_safe(&self) -> bool {
+        false
+    }
+
+    fn parameters_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "user_id": {
+                    "type": "integer",
+                    "description": "用户id"
+                },
+                "alias": {
+                    "type": "string",
+                    "description": "要删除的别称"
+                }
+            },
+            "required": ["user_id", "alias"]
+        })
+    }
+
+    async fn call(&self, args: Value, _msg: &Message) -> anyhow::Result<Value> {
+
+        let user_id = extract!(args, "user_id", as_u64) as usize;
+        let alias = extract!(args, "alias", as_str);
+
+        let mut map = self.map.lock().unwrap();
+        if !map.remove(user_id, alias.trim()) {
+            return Ok(Value::String(format!("{} 没有别称：{}", user_id, alias)));
+        }
+        map.save()?;
+        get_logger().info(&format!("删除了 {} 的别称：{}", user_id, alias));
+        Ok(Value::String(format!("已删除 {} 的别称：{}", user_id, alias)))
+    }
+}
+
+/// Finds who is meant by a name, through the aliases map.
+pub struct ResolveUserTool {
+    pub map: Arc<Mutex<AliasesMapping>>
+}
+
+#[async_trait]
+impl Tool for ResolveUserTool {
+    fn name(&self) -> &str {
+        "resolve_user"
+    }
+
+    fn description(&self) -> &str {
+        "根据别称查找用户id。当聊天中提到某人的名字而需要知道是哪位用户时调用"
+    }
+
+    fn parameters_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "name": {
+                    "type": "string",
+                    "description": "用户的名字或别称"
+                }
+            },
+            "required": ["name"]
+        })
+    }
+
+    async fn call(&self, args: Value, _msg: &Message) -> anyhow::Result<Value> {
+
+        let name = extract!(args, "name", as_str);
+        let user_ids = self.map.lock().unwrap().resolve(&name);
+
+        Ok(Value::String(match user_ids.as_slice() {
+            [] => format!("没有用户的别称是：{}", name),
+            [user_id] => format!("{} 是用户 {}", name, user_id),
+            _ => format!(
+                "有多位用户的别称是 {}：{}，请根据上下文判断",
+                name, user_ids.iter().map(|id| id.to_string()).collect::<Vec<String>>().join("、")
+            )
+        }))
+    }
+}
+
+/// Most dice rolled at once, so that results stay short.
+const MAX_DICE: usize = 100;
+const MAX_SIDES: u64 = 1_000_000;
+/// Bounds the modifier, so that totals can't overflow.
+const MAX_MODIFIER: i64 = 1_000_000;
+
+pub struct DiceTool {
+    rng: Mutex<StdRng>
+}
+
+impl DiceTool {
+    pub fn new() -> Self {
+        Self { rng: Mutex::new(StdRng::from_os_rng()) }
+    }
+
+    /// Rolls the same sequence every time for a given `seed`.
+    pub fn with_seed(seed: u64) -> Self {
+        Self { rng: Mutex::new(StdRng::seed_from_u64(seed)) }
+    }
+
+    /// Parses `NdM`, `NdM+K` or `NdM-K` into `(N, M, K)`. `N` defaults to 1.
+    pub fn parse(expression: &str) -> anyhow::Result<(usize, u64, i64)> {
+        let expr = expression.trim().to_lowercase().replace(' ', "");
+        let invalid = || anyhow::anyhow!("无法识别的表达式：{}，应形如 3d6+2", expression);
+
+        let (dice, modifier) = match expr.find(['+', '-']) {
+            Some(pos) => (&expr[..pos], expr[pos..].parse::<i64>().map_err(|_| invalid())?),
+            None => (expr.as_str(), 0)
+        };
+        let (count, sides) = dice.split_once('d').ok_or_else(invalid)?;
+        let count = if count.is_empty() { 1 } else { count.parse::<usize>().map_err(|_| invalid())? };
+        let sides = sides.parse::<u64>().map_err(|_| invalid())?;
+
+        if count == 0 || count > MAX_DICE {
+            return Err(anyhow::anyhow!("骰子数量应在 1 到 {} 之间", MAX_DICE));
+        }
+        if !(2..=MAX_SIDES).contains(&sides) {
+            return Err(anyhow::anyhow!("骰子面数应在 2 到 {} 之间", MAX_SIDES));
+        }
+        if !(-MAX_MODIFIER..=MAX_MODIFIER).contains(&modifier) {
+            return Err(anyhow::anyhow!("加值应在 -{} 到 {} 之间", MAX_MODIFIER, MAX_MODIFIER));
+        }
+
+        Ok((count, sides, modifier))
+    }
+
+    pub fn roll(&self, count: usize, sides: u64) -> Vec<u64> {
+        let mut rng = self.rng.lock().unwrap();
+        (0..count).map(|_| rng.random_range(1..=sides)).collect()
+    }
+}
+
+#[async_trait]
+impl Tool for DiceTool {
+    fn name(&self) -> &str {
+        "roll"
+    }
+
+    fn description(&self) -> &str {
+        "掷骰子或在范围内取随机数，用于群内小游戏"
+    }
+
+    fn parameters_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "expression": {
+                    "type": "string",
+                    "description": "骰子表达式，如 `3d6+2` 表示掷 3 个六面骰再加 2"
+                },
+                "min": {
+                    "type": "integer",
+                    "description": "不使用表达式时，随机数的最小值（含）"
+                },
+                "max": {
+                    "type": "integer",
+                    "description": "不使用表达式时，随机数的最大值（含）"
+                }
+            }
+        })
+    }
+
+    async fn call(&self, args: Value, _msg: &Message) -> anyhow::Result<Value> {
+        if let Some(expression) = extract_optional!(args, "expression", as_str) {
+            let (count, sides, modifier) = DiceTool::parse(&expression)?;
+            let rolls = self.roll(count, sides);
+            let total = rolls.iter().sum::<u64>() as i64 + modifier;
+
+            let rolls = rolls.iter().map(u64::to_string).collect::<Vec<String>>().join(", ");
+            let modifier = match modifier {
+                0 => "".to_string(),
+                m if m > 0 => format!(" + {}", m),
+                m => format!(" - {}", -m)
+            };
+            return Ok(Value::String(format!("{}：[{}]{} = {}", expression.trim(), rolls, modifier, total)));
+        }
+
+        let min = extract_optional!(args, "min", as_i64).unwrap_or(1);
+        let max = extract!(args, "max", as_i64);
+        if min > max {
+            return Err(anyhow::anyhow!("最小值 {} 大于最大值 {}", min, max));
+        }
+
+        let result = self.rng.lock().unwrap().random_range(min..=max);
+        Ok(Value::String(format!("在 {} 到 {} 之间取到了 {}", min, max, result)))
+    }
+}
+
+/// The QQ face id of a thumbs-up.
+const THUMBS_UP: usize = 76;
+
+/// Reacts to the triggering message with an emoji, acknowledging it without a text reply.
+pub struct ReactTool;
+
+#[async_trait]
+impl Tool for ReactTool {
+    fn name(&self) -> &str {
+        "react"
+    }
+
+    fn description(&self) -> &str {
+        "给需要回复的消息贴一个表情作为回应。适合只需表示收到或认同、不值得文字回复的情况，使用后可以不再输出文字"
+    }
+
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    fn parameters_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "emoji_id": {
+                    "type": "integer",
+                    "description": "QQ 表情 id，默认为 76（赞）"
+                }
+            }
+        })
+    }
+
+    async fn call(&self, args: Value, msg: &Message) -> anyhow::Result<Value> {
+        let emoji_id = extract_optional!(args, "emoji_id", as_u64).map_or(THUMBS_UP, |id| id as usize);
+        get_poster().react(msg.message_id, emoji_id).await
+            .map_err(|err| anyhow::anyhow!("回应失败：{}", err))?;
+        Ok(Value::String("已回应".to_string()))
+    }
+}
+
+pub struct TimeTool;
+
+impl TimeTool {
+    fn parse_tz(name: &str) -> anyhow::Result<Tz> {
+        name.trim().parse::<Tz>().map_err(|_| anyhow::anyhow!("未知的时区：{}，应为 IANA 名称，如 Asia/Shanghai", name))
+    }
+
+    /// Like `2025-01-01 星期三 08:00:00 (Asia/Shanghai, UTC+08:00)`.
+    fn format<T: TimeZone>(time: &DateTime<T>, zone: &str) -> String where T::Offset: std::fmt::Display {
+        let weekday = match time.weekday() {
+            Weekday::Mon => "星期一",
+            Weekday::Tue => "星期二",
+            Weekday::Wed => "星期三",
+            Weekday::Thu => "星期四",
+            Weekday::Fri => "星期五",
+            Weekday::Sat => "星期六",
+            Weekday::Sun => "星期日"
+        };
+        format!("{} {} {} ({}, UTC{})", time.format("%Y-%m-%d"), weekday, time.format("%H:%M:%S"), zone, time.format("%:z"))
+    }
+}
+
+#[async_trait]
+impl Tool for TimeTool {
+    fn name(&self) -> &str {
+        "now"
+    }
+
+    fn description(&self) -> &str {
+        "获取当前的日期、星期和时间，也可以在时区之间换算时间"
+    }
+
+    fn parameters_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "timezone": {
+                    "type": "string",
+                    "description": "要显示的时区的 IANA 名称，如 `America/New_York`。不填则使用本地时区"
+                },
+                "time": {
+                    "type": "string",
+                    "description": "要换算的时间，格式为 `YYYY-MM-DD HH:MM`。不填则为当前时间"
+                },
+                "from_timezone": {
+                    "type": "string",
+                    "description": "`time` 所在时区的 IANA 名称。不填则为本地时区"
+                }
+            }
+        })
+    }
+
+    async fn call(&self, args: Value, _msg: &Message) -> anyhow::Result<Value> {
+        let time = match extract_optional!(args, "time", as_str) {
+            Some(time) => {
+                let naive = NaiveDateTime::parse_from_str(time.trim(), "%Y-%m-%d %H:%M")
+                    .map_err(|_| anyhow::anyhow!("无法识别的时间：{}，格式应为 YYYY-MM-DD HH:MM", time))?;
+                let utc = match extract_optional!(args, "from_timezone", as_str) {
+                    Some(zone) => TimeTool::parse_tz(&zone)?.from_local_datetime(&naive).earliest(),
+                    None => Local.from_local_datetime(&naive).earliest().map(|t| t.with_timezone(&Tz::UTC))
+                };
+                utc.ok_or_else(|| anyhow::anyhow!("该时区中不存在时间 {}", time))?.with_timezone(&chrono::Utc)
+            }
+            None => chrono::Utc::now()
+        };
+
+        let result = match extract_optional!(args, "timezone", as_str) {
+            Some(zone) => {
+                let tz = TimeTool::parse_tz(&zone)?;
+                TimeTool::format(&time.with_timezone(&tz), tz.name())
+            }
+            None => TimeTool::format(&time.with_timezone(&Local), "本地时间")
+        };
+
+        Ok(Value::String(result))
+    }
+}
+
+pub struct CalcTool;
+
+impl CalcTool {
+    /// Evaluates plain arithmetic. Names of any kind, so functions and
+    /// constants too, are rejected.
+    pub fn eval(expression: &str) -> anyhow::Result<f64> {
+        if let Some(c) = expression.chars().find(|c| !(c.is_ascii_digit() || c.is_whitespace() || "+-*/%^().".contains(*c))) {
+            return Err(anyhow::anyhow!("表达式中含有不支持的字符：{}", c));
+        }
+
+        let expr = expression.parse::<meval::Expr>()
+            .map_err(|err| anyhow::anyhow!("无法解析表达式：{}", err))?;
+        let result = expr.eval_with_context(meval::Context::empty())
+            .map_err(|err| anyhow::anyhow!("无法计算表达式：{}", err))?;
+
+        if result.is_finite() { Ok(result) }
+        else { Err(anyhow::anyhow!("计算结果不是有限的数（可能除以了 0）")) }
+    }
+}
+
+#[async_trait]
+impl Tool for CalcTool {
+    fn name(&self) -> &str {
+        "calc"
+    }
+
+    fn description(&self) -> &str {
+        "计算四则运算表达式，支持 + - * / % ^ 和括号。需要计算数值时必须调用，不要心算"
+    }
+
+    fn parameters_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "expression": {
+                    "type": "string",
+                    "description": "要计算的表达式，如 `(1 + 2) * 3 ^ 2`"
+                }
+            },
+            "required": ["expression"]
+        })
+    }
+
+    async fn call(&self, args: Value, _msg: &Message) -> anyhow::Result<Value> {
+        let expression = extract!(args, "expression", as_str);
+        let result = CalcTool::eval(&expression)?;
+        Ok(Value::String(format!("{} = {}", expression.trim(), result)))
+    }
+}
+
+/// Reminders further away than this are refused.
+const MAX_REMINDER_DAYS: i64 = 365;
+
+pub struct ReminderTool {
+    pub store: Arc<Mutex<Reminders>>
+}
+
+#[async_trait]
+impl Tool for ReminderTool {
+    fn name(&self) -> &str {
+        "set_reminder"
+    }
+
+    fn description(&self) -> &str {
+        "设置定时提醒，到时间后在当前聊天中提醒用户。如“提醒我20分钟后开会”"
+    }
+
+    fn parallel_safe(&self) -> bool {
+        false
+    }
+
+    fn parameters_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "content": {
+                    "type": "string",
+                    "description": "提醒的内容"
+                },
+                "delay_secs": {
+                    "type": "integer",
+                    "description": "多少秒后提醒"
+                },
+                "at": {
+                    "type": "string",
+                    "description": "不使用 delay_secs 时，提醒的本地时间，格式为 `YYYY-MM-DD HH:MM`"
+                }
+            },
+            "required": ["content"]
+        })
+    }
+
+    async fn call(&self, args: Value, msg: &Message) -> anyhow::Result<Value> {
+        let content = extract!(args, "content", as_str);
+
+        let fire_at = if let Some(delay) = extract_optional!(args, "delay_secs", as_i64) {
+            // Checked first, so that huge delays can't overflow the date below.
+            if delay > MAX_REMINDER_DAYS * 24 * 60 * 60 {
+                return Err(anyhow::anyhow!("最多只能设置 {} 天内的提醒", MAX_REMINDER_DAYS));
+            }
+            TimeDelta::try_seconds(delay.max(0))
+                .and_then(|delay| Local::now().checked_add_signed(delay))
+                .ok_or_else(|| anyhow::anyhow!("无法设置 {} 秒后的提醒", delay))?
+        } else if let Some(at) = extract_optional!(args, "at", as_str) {
+            let naive = NaiveDateTime::parse_from_str(at.trim(), "%Y-%m-%d %H:%M")
+                .map_err(|_| anyhow::anyhow!("无法识别的时间：{}，格式应为 YYYY-MM-DD HH:MM", at))?;
+            Local.from_local_datetime(&naive).earliest()
+                .ok_or_else(|| anyhow::anyhow!("本地时区中不存在时间 {}", at))?
+        } else {
+            return Err(anyhow::anyhow!("Missing argument: delay_secs or at"));
+        };
+
+        if fire_at < Local::now() {
+            return Err(anyhow::anyhow!("提醒时间 {} 已经过去了", fire_at.format("%Y-%m-%d %H:%M")));
+        }
+        if fire_at - Local::now() > TimeDelta::days(MAX_REMINDER_DAYS) {
+            return Err(anyhow::anyhow!("最多只能设置 {} 天内的提醒", MAX_REMINDER_DAYS));
+        }
+
+        reminders::schedule(&self.store, Reminder {
+            id: uuid::Uuid::new_v4(),
+            scope: Scope::from(msg),
+            user_id: msg.sender.user_id,
+            content: content.clone(),
+            fire_at
+        })?;
+
+        get_logger().info(&format!("为 {} 设置了提醒：{}", msg.sender.user_id, content));
+        Ok(Value::String(format!("已设置提醒，将于 {} 提醒：{}", fire_at.format("%Y-%m-%d %H:%M:%S"), content)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reminder_rejects_huge_delay() {
+        let tool = ReminderTool { store: Arc::new(Mutex::new(Reminders::default())) };
+        let msg = Message::builder().sender(1).build();
+        for delay in [i64::MAX, MAX_REMINDER_DAYS * 24 * 60 * 60 + 1] {
+            let result = tool.call(json!({ "content": "开会", "delay_secs": delay }), &msg).await;
+            assert!(result.is_err(), "超出范围的延迟应报错而不是崩溃：{}", delay);
+        }
+        assert!(tool.store.lock().unwrap().pending().is_empty(), "不应保存无效的提醒");
+    }
+
+    #[test]
+    fn test_dice_parse() -> anyhow::Result<()> {
+        assert_eq!(DiceTool::parse("3d6+2")?, (3, 6, 2));
+        assert_eq!(DiceTool::parse("d20")?, (1, 20, 0));
+        assert_eq!(DiceTool::parse("2D10 - 1")?, (2, 10, -1));
+
+        assert!(DiceTool::parse("101d6").is_err(), "超过上限的骰子数量应被拒绝");
+        assert!(DiceTool::parse("3d1").is_err(), "单面骰应被拒绝");
+        assert!(DiceTool::parse("1d6+9223372036854775807").is_err(), "过大的加值应被拒绝");
+        assert!(DiceTool::parse("1d6-9223372036854775808").is_err(), "过小的加值应被拒绝");
+        assert!(DiceTool::parse("hello").is_err(), "非法表达式应被拒绝");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tool_error_kinds() {
+        let not_found: ToolError = anyhow::Error::from(MemoryError::NotFound(3)).into();
+        assert!(matches!(not_found, ToolError::Invalid(_)), "不存在的记忆应视为调用有误");
+
+        let embed: ToolError = anyhow::Error::from(MemoryError::Embed("timeout".to_string())).into();
+        assert!(matches!(embed, ToolError::Transient(_)), "向量化失败应视为暂时不可用");
+
+        let other: ToolError = anyhow::anyhow!("骰子数量应在 1 到 100 之间").into();
+        assert!(matches!(other, ToolError::Failed(_)));
+    }
+
+    #[test]
+    fn test_dice_seeded() {
+        let rolls = DiceTool::with_seed(42).roll(10, 6);
+        assert_eq!(rolls, DiceTool::with_seed(42).roll(10, 6), "相同种子应得到相同结果");
+        assert!(rolls.iter().all(|roll| (1..=6).contains(roll)), "点数超出范围");
+    }
+
+    #[test]
+    fn test_time_format() -> anyhow::Result<()> {
+        let tz = TimeTool::parse_tz("Asia/Shanghai")?;
+        let time = tz.with_ymd_and_hms(2025, 1, 1, 8, 0, 0).unwrap();
+        assert_eq!(TimeTool::format(&time, tz.name()), "2025-01-01 星期三 08:00:00 (Asia/Shanghai, UTC+08:00)");
+
+        assert!(TimeTool::parse_tz("Mars/Olympus").is_err(), "未知时区应被拒绝");
+        Ok(())
+    }
+
+    #[test]
+    fn test_calc() -> anyhow::Result<()> {
+        assert_eq!(CalcTool::eval("(1 + 2) * 3 ^ 2")?, 27.0);
+        assert_eq!(CalcTool::eval("7 % 4 - 0.5")?, 2.5);
+
+        assert!(CalcTool::eval("sin(1)").is_err(), "函数调用应被拒绝");
+        assert!(CalcTool::eval("pi * 2").is_err(), "常量应被拒绝");
+        assert!(CalcTool::eval("1 / 0").is_err(), "除以 0 应报错");
+        assert!(CalcTool::eval("1 +").is_err(), "不完整的表达式应报错");
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncate_result() {
+        let short = Value::String("短结果".to_string());
+        assert_eq!(truncate_result(short.clone(), 10), short);
+
+        let long = truncate_result(Value::String("啊".repeat(20)), 10);
+        let long = long.as_str().unwrap_or_default();
+        assert!(long.starts_with(&"啊".repeat(10)), "应保留前 10 个字");
+        assert!(long.contains("已省略 10 字"), "应注明省略的字数");
+    }
+
+    #[test]
+    fn test_find_bvid() {
+        assert_eq!(BilibiliTool::find_bvid("BV1GJ411x7h7").as_deref(), Some("BV1GJ411x7h7"));
+        assert_eq!(
+            BilibiliTool::find_bvid("https://www.bilibili.com/video/BV1GJ411x7h7/?share_source=qq").as_deref(),
+            Some("BV1GJ411x7h7")
+        );
+        assert_eq!(BilibiliTool::find_bvid("BVtooshort"), None);
+    }
+}
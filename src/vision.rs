@@ -0,0 +1,116 @@
+use std::time::Duration;
+
+use serde_json::{Value, json};
+
+use crate::{get_config, get_logger, objects::{Message, MessageArrayItem}};
+
+/// Captions images with a multimodal model, so that chat history and memory
+/// extraction see what an image shows instead of just its file name.
+/// Needs `api.vision_root` and `api.vision_key`.
+pub struct Vision {
+    client: reqwest::Client,
+    api_root: String,
+    api_key: String,
+    model: String
+}
+
+impl Vision {
+    pub fn new(model: &str) -> anyhow::Result<Self> {
+        Self::with_api(&get_config().api.vision_root()?, &get_config().api.vision_key()?, model)
+    }
+
+    pub fn with_api(api_root: &str, api_key: &str, model: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: reqwest::ClientBuilder::new()
+                .timeout(Duration::from_secs(20))
+                .build()?,
+            api_root: api_root.to_string(),
+            api_key: api_key.to_string(),
+            model: model.to_string()
+        })
+    }
+
+    /// Whether `msg` has an image without a summary.
+    pub fn needs_caption(msg: &Message) -> bool {
+        msg.array.iter().any(|item| matches!(item, MessageArrayItem::Image { summary, .. } if !has_summary(summary)))
+    }
+
+    /// Fills in the summary of every image in `msg` that has none with a caption.
+    /// Images that fail to be captioned are left as they are.
+    pub async fn describe(&self, msg: &mut Message) {
+        for item in msg.array.iter_mut() {
+            let MessageArrayItem::Image { summary, url, .. } = item else { continue };
+            if has_summary(summary) {
+                continue;
+            }
+            match self.caption(url).await {
+                Ok(caption) => *summary = Some(caption),
+                Err(err) => get_logger().warn(&format!("Error captioning image: {}", err))
+            }
+        }
+    }
+
+    pub async fn caption(&self, url: &str) -> anyhow::Result<String> {
+        let resp = self.client.post(&self.api_root)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .json(&json!({
+                "model": self.model,
+                "messages": [{
+                    "role": "user",
+                    "content": [
+                        { "type": "image_url", "image_url": { "url": url } },
+                        { "type": "text", "text": "用一句话描述这张图片的内容。如果图中有文字，一并写出。" }
+                    ]
+                }]
+            }))
+            .send().await?
+            .error_for_status()?
+            .json::<Value>().await?;
+
+        resp.pointer("/choices/0/message/content")
+            .and_then(|content| content.as_str())
+            .map(|content| content.trim().to_string())
+            .filter(|content| !content.is_empty())
+            .ok_or_else(|| anyhow::anyhow!("Empty caption"))
+    }
+}
+
+fn has_summary(summary: &Option<String>) -> bool {
+    summary.as_deref().is_some_and(|summary| !summary.trim().is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_describe() -> anyhow::Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server.mock("POST", "/")
+            .match_header("Authorization", "Bearer key")
+            .match_body(mockito::Matcher::PartialJson(json!({ "model": "vl" })))
+            .with_body(json!({ "choices": [{ "message": { "content": " 一只猫 " } }] }).to_string())
+            .expect(1)
+            .create_async().await;
+
+        let vision = Vision::with_api(&server.url(), "key", "vl")?;
+        let image = |summary: Option<&str>| MessageArrayItem::Image {
+            summary: summary.map(str::to_string),
+            file: None,
+            url: "http://x/1.png".to_string(),
+            file_size: None
+        };
+        let mut msg = Message::builder().sender(1).group(1).text("看")
+            .item(image(None))
+            .item(image(Some("[表情]")))
+            .build();
+        assert!(Vision::needs_caption(&msg));
+
+        vision.describe(&mut msg).await;
+        assert!(matches!(&msg.array[1], MessageArrayItem::Image { summary: Some(summary), .. } if summary == "一只猫"));
+        assert!(matches!(&msg.array[2], MessageArrayItem::Image { summary: Some(summary), .. } if summary == "[表情]"), "已有描述的图片不应再请求");
+        assert!(!Vision::needs_caption(&msg));
+        mock.assert_async().await;
+        Ok(())
+    }
+}